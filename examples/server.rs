@@ -4,7 +4,7 @@ use uuid::Uuid;
 
 use ble_peripheral_rust::{
     gatt::{
-        characteristic::Characteristic,
+        characteristic::{Characteristic, CharacteristicId},
         descriptor::Descriptor,
         peripheral_event::{
             PeripheralEvent, ReadRequestResponse, RequestResponse, WriteRequestResponse,
@@ -26,11 +26,12 @@ async fn main() {
 }
 
 async fn start_app() {
+    let service_uuid = Uuid::from_short(0x1234_u16);
     let char_uuid = Uuid::from_short(0x2A3D_u16);
 
     // Define Service With Characteristics
     let service = Service {
-        uuid: Uuid::from_short(0x1234_u16),
+        uuid: service_uuid,
         primary: true,
         characteristics: vec![
             Characteristic {
@@ -45,21 +46,22 @@ async fn start_app() {
                     AttributePermission::Writeable,
                 ],
                 value: None,
-                descriptors: vec![Descriptor {
-                    uuid: Uuid::from_short(0x2A13_u16),
-                    value: Some(vec![0, 1]),
-                    ..Default::default()
-                }],
+                descriptors: vec![Descriptor::user_description("Echo")],
+                ..Default::default()
             },
             Characteristic {
                 uuid: Uuid::from_string("1209"),
                 ..Default::default()
             },
         ],
+        ..Default::default()
     };
 
     let (sender_tx, mut receiver_rx) = mpsc::channel::<PeripheralEvent>(256);
 
+    #[cfg(all(any(target_os = "linux", target_os = "android"), not(feature = "mock")))]
+    let mut peripheral = Peripheral::new(sender_tx, None).await.unwrap();
+    #[cfg(any(not(any(target_os = "linux", target_os = "android")), feature = "mock"))]
     let mut peripheral = Peripheral::new(sender_tx).await.unwrap();
 
     // Handle Updates
@@ -86,6 +88,10 @@ async fn start_app() {
     }
     log::info!("Advertising Started");
 
+    for attribute in peripheral.attribute_table().await.unwrap_or_default() {
+        log::debug!("Attribute: {attribute:?}");
+    }
+
     // Write in console to send to characteristic update to subscribed clients
     let stdin = io::stdin();
     for line in stdin.lock().lines() {
@@ -93,7 +99,10 @@ async fn start_app() {
             Ok(input) => {
                 println!("Writing: {input} to {char_uuid}");
                 peripheral
-                    .update_characteristic(char_uuid, input.into())
+                    .update_characteristic(
+                        CharacteristicId::new(service_uuid, char_uuid),
+                        input.into(),
+                    )
                     .await
                     .unwrap();
             }
@@ -111,6 +120,18 @@ pub fn handle_updates(update: PeripheralEvent) {
         PeripheralEvent::StateUpdate { is_powered } => {
             log::info!("PowerOn: {is_powered:?}")
         }
+        PeripheralEvent::ClientConnected {
+            client,
+            address_type,
+        } => {
+            log::info!("ClientConnected: {client} {address_type:?}")
+        }
+        PeripheralEvent::ClientDisconnected {
+            client,
+            address_type,
+        } => {
+            log::info!("ClientDisconnected: {client} {address_type:?}")
+        }
         PeripheralEvent::CharacteristicSubscriptionUpdate {
             request,
             subscribed,
@@ -143,5 +164,44 @@ pub fn handle_updates(update: PeripheralEvent) {
                 })
                 .unwrap();
         }
+        PeripheralEvent::DescriptorReadRequest {
+            request,
+            offset,
+            responder,
+        } => {
+            log::info!("DescriptorReadRequest: {request:?} Offset: {offset}");
+            responder
+                .send(ReadRequestResponse {
+                    value: vec![0],
+                    response: RequestResponse::Success,
+                })
+                .unwrap();
+        }
+        PeripheralEvent::DescriptorWriteRequest {
+            request,
+            offset,
+            value,
+            responder,
+        } => {
+            log::info!("DescriptorWriteRequest: {request:?} Value: {value:?} Offset: {offset}");
+            responder
+                .send(WriteRequestResponse {
+                    response: RequestResponse::Success,
+                })
+                .unwrap();
+        }
+        PeripheralEvent::PreparedWriteCancelled { request } => {
+            log::info!("PreparedWriteCancelled: {request:?}")
+        }
+        PeripheralEvent::RequestUnanswered {
+            request,
+            descriptor,
+            timed_out,
+            response,
+        } => {
+            log::warn!(
+                "RequestUnanswered: {request:?} Descriptor: {descriptor:?} TimedOut: {timed_out} Response: {response:?}"
+            )
+        }
     }
 }
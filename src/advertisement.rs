@@ -0,0 +1,256 @@
+use crate::error::{Error, ErrorType};
+use std::{collections::BTreeMap, time::Duration};
+use uuid::Uuid;
+
+/// Size limit of both legacy advertising payloads
+pub const MAX_LEGACY_PAYLOAD_LEN: usize = 31;
+
+// Lower 96 bits of the Bluetooth base uuid 00000000-0000-1000-8000-00805F9B34FB
+const BASE_UUID_LOW_BITS: u128 = 0x0000_1000_8000_0080_5F9B_34FB;
+const BASE_UUID_MASK: u128 = (1 << 96) - 1;
+
+/// Identifies an advertisement added with `add_advertisement`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AdvertisementId(pub(crate) u64);
+
+impl AdvertisementId {
+    // The advertisement controlled by start_advertising and start_advertising_with
+    pub(crate) const PRIMARY: AdvertisementId = AdvertisementId(0);
+
+    pub(crate) fn next(&self) -> AdvertisementId {
+        AdvertisementId(self.0 + 1)
+    }
+}
+
+/// Fields the Bluetooth stack fills in itself when listed in `AdvertisementConfig::includes`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AdvertisementInclude {
+    TxPower,
+    Appearance,
+    LocalName,
+}
+
+/// Fields that can be moved from the advertising data to the scan response
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AdvertisementField {
+    LocalName,
+    ServiceUuids,
+    ServiceData,
+    ManufacturerData,
+}
+
+/// Raw advertising data and scan response, as sent over the air
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdvertisementPayload {
+    pub advertising_data: Vec<u8>,
+    pub scan_response: Vec<u8>,
+}
+
+/// Content and timing of an advertisement, see `start_advertising_with`
+///
+/// Backends advertise what their platform allows: BlueZ supports every field,
+/// CoreBluetooth only the local name and service uuids, and Windows the service data
+/// of registered services. Unsupported fields are ignored with a warning.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdvertisementConfig {
+    pub local_name: Option<String>,
+    pub service_uuids: Vec<Uuid>,
+    /// Keyed by Bluetooth SIG company identifier
+    pub manufacturer_data: BTreeMap<u16, Vec<u8>>,
+    pub service_data: BTreeMap<Uuid, Vec<u8>>,
+    pub solicit_uuids: Vec<Uuid>,
+    pub appearance: Option<u16>,
+    /// Requested TX power in dBm
+    pub tx_power: Option<i16>,
+    /// Non-connectable advertisements only broadcast their data, e.g. for beacons
+    pub connectable: bool,
+    pub discoverable: bool,
+    /// Time after which the peripheral stops being discoverable, zero disables the timeout
+    pub discoverable_timeout: Option<Duration>,
+    pub min_interval: Option<Duration>,
+    pub max_interval: Option<Duration>,
+    pub includes: Vec<AdvertisementInclude>,
+    /// Fields sent in the scan response instead of the advertising data
+    ///
+    /// BlueZ always sends the local name in the scan response and fails with `NotSupported`
+    /// when other fields are listed, CoreBluetooth and Windows lay out the payloads themselves.
+    pub scan_response: Vec<AdvertisementField>,
+}
+
+impl AdvertisementConfig {
+    /// Discoverable advertisement with a local name and service uuids, as used by `start_advertising`
+    pub fn new(name: &str, uuids: &[Uuid]) -> Self {
+        AdvertisementConfig {
+            local_name: Some(name.to_string()),
+            service_uuids: uuids.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    /// Encode the config into legacy advertising PDUs, checking that both payloads fit
+    ///
+    /// A local name that does not fit is sent shortened. Fields filled in by the stack
+    /// through `includes` are left out, except the TX power level which is encoded with
+    /// `tx_power` or 0 dBm.
+    pub fn encode(&self) -> Result<AdvertisementPayload, Error> {
+        let mut advertising_data: Vec<u8> = Vec::new();
+        let mut scan_response: Vec<u8> = Vec::new();
+
+        // LE General Discoverable or no discoverable mode, BR/EDR not supported
+        let flags: u8 = if self.discoverable { 0x06 } else { 0x04 };
+        push_ad_structure(&mut advertising_data, 0x01, &[flags])?;
+
+        // Movable fields are encoded on their own, then appended to the payload they belong to
+        let mut service_uuids: Vec<u8> = Vec::new();
+        push_uuid_lists(&mut service_uuids, &self.service_uuids, [0x03, 0x05, 0x07])?;
+
+        let mut service_data: Vec<u8> = Vec::new();
+        for (uuid, data) in &self.service_data {
+            let mut value = uuid_to_le_bytes(uuid);
+            let ad_type = match value.len() {
+                2 => 0x16,
+                4 => 0x20,
+                _ => 0x21,
+            };
+            value.extend_from_slice(data);
+            push_ad_structure(&mut service_data, ad_type, &value)?;
+        }
+
+        let mut manufacturer_data: Vec<u8> = Vec::new();
+        for (company, data) in &self.manufacturer_data {
+            let mut value = company.to_le_bytes().to_vec();
+            value.extend_from_slice(data);
+            push_ad_structure(&mut manufacturer_data, 0xFF, &value)?;
+        }
+
+        for (field, data) in [
+            (AdvertisementField::ServiceUuids, service_uuids),
+            (AdvertisementField::ServiceData, service_data),
+            (AdvertisementField::ManufacturerData, manufacturer_data),
+        ] {
+            if self.scan_response.contains(&field) {
+                scan_response.extend(data);
+            } else {
+                advertising_data.extend(data);
+            }
+        }
+
+        push_uuid_lists(
+            &mut advertising_data,
+            &self.solicit_uuids,
+            [0x14, 0x1F, 0x15],
+        )?;
+        if let Some(appearance) = self.appearance {
+            push_ad_structure(&mut advertising_data, 0x19, &appearance.to_le_bytes())?;
+        }
+        if self.includes.contains(&AdvertisementInclude::TxPower) {
+            let tx_power = self.tx_power.unwrap_or(0).clamp(-127, 20) as i8;
+            push_ad_structure(&mut advertising_data, 0x0A, &tx_power.to_le_bytes())?;
+        }
+
+        // Like the platform stacks, shorten the name to the space left in its payload
+        if let Some(name) = &self.local_name {
+            let payload = if self.scan_response.contains(&AdvertisementField::LocalName) {
+                &mut scan_response
+            } else {
+                &mut advertising_data
+            };
+            let available = MAX_LEGACY_PAYLOAD_LEN.saturating_sub(payload.len() + 2);
+            if name.len() <= available {
+                push_ad_structure(payload, 0x09, name.as_bytes())?;
+            } else if available > 0 {
+                let mut end = available;
+                while !name.is_char_boundary(end) {
+                    end -= 1;
+                }
+                push_ad_structure(payload, 0x08, &name.as_bytes()[..end])?;
+            }
+        }
+
+        for (name, data) in [
+            ("Advertising data", &advertising_data),
+            ("Scan response", &scan_response),
+        ] {
+            if data.len() > MAX_LEGACY_PAYLOAD_LEN {
+                return Err(Error::from_string(
+                    format!(
+                        "{name} is {} bytes, the limit is {MAX_LEGACY_PAYLOAD_LEN}",
+                        data.len()
+                    ),
+                    ErrorType::InvalidAdvertisement,
+                ));
+            }
+        }
+        Ok(AdvertisementPayload {
+            advertising_data,
+            scan_response,
+        })
+    }
+}
+
+impl Default for AdvertisementConfig {
+    fn default() -> Self {
+        AdvertisementConfig {
+            local_name: None,
+            service_uuids: Vec::new(),
+            manufacturer_data: BTreeMap::new(),
+            service_data: BTreeMap::new(),
+            solicit_uuids: Vec::new(),
+            appearance: None,
+            tx_power: None,
+            connectable: true,
+            discoverable: true,
+            discoverable_timeout: None,
+            min_interval: None,
+            max_interval: None,
+            includes: Vec::new(),
+            scan_response: Vec::new(),
+        }
+    }
+}
+
+// The length byte covers the AD type and the value
+fn push_ad_structure(payload: &mut Vec<u8>, ad_type: u8, value: &[u8]) -> Result<(), Error> {
+    let length = u8::try_from(value.len() + 1).map_err(|_| {
+        Error::from_string(
+            format!(
+                "AD structure 0x{ad_type:02X} is {} bytes, the limit is 254",
+                value.len()
+            ),
+            ErrorType::InvalidAdvertisement,
+        )
+    })?;
+    payload.push(length);
+    payload.push(ad_type);
+    payload.extend_from_slice(value);
+    Ok(())
+}
+
+// One list per uuid size, with the AD types for 16, 32 and 128 bit uuids
+fn push_uuid_lists(payload: &mut Vec<u8>, uuids: &[Uuid], ad_types: [u8; 3]) -> Result<(), Error> {
+    for (len, ad_type) in [(2, ad_types[0]), (4, ad_types[1]), (16, ad_types[2])] {
+        let value: Vec<u8> = uuids
+            .iter()
+            .map(uuid_to_le_bytes)
+            .filter(|bytes| bytes.len() == len)
+            .flatten()
+            .collect();
+        if !value.is_empty() {
+            push_ad_structure(payload, ad_type, &value)?;
+        }
+    }
+    Ok(())
+}
+
+// Shortest little endian form of the uuid, uuids on the base uuid shrink to 16 or 32 bits
+fn uuid_to_le_bytes(uuid: &Uuid) -> Vec<u8> {
+    let value = uuid.as_u128();
+    if value & BASE_UUID_MASK != BASE_UUID_LOW_BITS {
+        return value.to_le_bytes().to_vec();
+    }
+    let short = (value >> 96) as u32;
+    match u16::try_from(short) {
+        Ok(short) => short.to_le_bytes().to_vec(),
+        Err(_) => short.to_le_bytes().to_vec(),
+    }
+}
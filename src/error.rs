@@ -1,29 +1,70 @@
-use std::{error, fmt};
+use std::{error, fmt, sync::Arc};
+use tokio::sync::{mpsc, oneshot};
 
-#[derive(Debug, Clone)]
+/// Kind of failure, to decide whether to retry, reset the adapter or fail fast
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ErrorType {
+    /// There is no Bluetooth adapter, it was removed or the Bluetooth service is not running
+    AdapterNotFound,
+    /// The adapter is powered off, `StateUpdate` reports when it is powered again
+    NotPowered,
+    /// The application is not allowed to use Bluetooth
+    PermissionDenied,
+    /// The service is not registered
+    ServiceNotFound,
+    /// The characteristic is not part of a registered service
+    CharacteristicNotFound,
+    /// The advertisement id is not advertised
+    AdvertisementNotFound,
+    /// The client is not connected
+    ClientNotFound,
+    /// No advertising instance is left, see `supported_advertising_instances`
+    AdvertisingLimitReached,
+    /// The same operation is still running
+    AlreadyInProgress,
+    /// The platform did not answer in time
+    Timeout,
+    /// The platform does not offer the operation
+    NotSupported,
+    InvalidAdvertisement,
+    InvalidService,
+    /// An internal channel closed, the peripheral has to be created again
+    ChannelError,
+    /// Other failures of the platform, `source()` returns the platform's error if there is one
     Bluez,
     CoreBluetooth,
     Windows,
-    PermissionDenied,
-    ChannelError,
+    Mock,
 }
 
 impl From<ErrorType> for &'static str {
     fn from(error_type: ErrorType) -> &'static str {
         match error_type {
+            ErrorType::AdapterNotFound => "AdapterNotFound",
+            ErrorType::NotPowered => "NotPowered",
+            ErrorType::PermissionDenied => "PermissionDenied",
+            ErrorType::ServiceNotFound => "ServiceNotFound",
+            ErrorType::CharacteristicNotFound => "CharacteristicNotFound",
+            ErrorType::AdvertisementNotFound => "AdvertisementNotFound",
+            ErrorType::ClientNotFound => "ClientNotFound",
+            ErrorType::AdvertisingLimitReached => "AdvertisingLimitReached",
+            ErrorType::AlreadyInProgress => "AlreadyInProgress",
+            ErrorType::Timeout => "Timeout",
+            ErrorType::NotSupported => "NotSupported",
+            ErrorType::InvalidAdvertisement => "InvalidAdvertisement",
+            ErrorType::InvalidService => "InvalidService",
+            ErrorType::ChannelError => "ChannelError",
             ErrorType::Bluez => "Bluez",
             ErrorType::CoreBluetooth => "CoreBluetooth",
             ErrorType::Windows => "Windows",
-            ErrorType::PermissionDenied => "PermissionDenied",
-            ErrorType::ChannelError => "ChannelError",
+            ErrorType::Mock => "Mock",
         }
     }
 }
 
 impl fmt::Display for ErrorType {
-    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
-        let error_type: &str = self.clone().into();
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let error_type: &str = (*self).into();
         write!(f, "<BlePeripheralRust {} Error>", error_type)
     }
 }
@@ -34,51 +75,62 @@ impl error::Error for ErrorType {}
 pub struct Error {
     name: String,
     description: String,
-    combined_description: String,
     error_type: ErrorType,
+    source: Option<Arc<dyn error::Error + Send + Sync>>,
 }
 
 impl Error {
     pub fn new<T: Into<String>>(name: T, description: T, error_type: ErrorType) -> Self {
         let name: String = name.into();
         let description: String = description.into();
-        let combined_description = format!("{}: {}", name, description);
         Error {
             name,
             description,
-            combined_description,
             error_type,
+            source: None,
         }
     }
 
     pub fn from_type(error_type: ErrorType) -> Self {
         let name: String = error_type.to_string();
         let description: String = error_type.to_string();
-        let combined_description = format!("{}: {}", name, description);
         Error {
             name,
             description,
-            combined_description,
             error_type,
+            source: None,
         }
     }
 
     pub fn from_string(error: String, error_type: ErrorType) -> Self {
         let name: String = error_type.to_string();
         let description: String = error;
-        let combined_description = format!("{}: {}", name, description);
         Error {
             name,
             description,
-            combined_description,
             error_type,
+            source: None,
         }
     }
+
+    /// Wrap an error of the platform, returned by `source()`
+    pub fn from_source<E>(source: E, error_type: ErrorType) -> Self
+    where
+        E: error::Error + Send + Sync + 'static,
+    {
+        let mut error = Error::from_string(source.to_string(), error_type);
+        error.source = Some(Arc::new(source));
+        error
+    }
+
+    pub fn error_type(&self) -> ErrorType {
+        self.error_type
+    }
 }
 
 impl fmt::Display for Error {
-    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
-        let error_type: &str = self.error_type.clone().into();
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let error_type: &str = self.error_type.into();
         write!(
             f,
             "**BlePeripheralRust {} Error**\n\n\t{}:\n\t\t{}",
@@ -88,11 +140,21 @@ impl fmt::Display for Error {
 }
 
 impl error::Error for Error {
-    fn description(self: &Self) -> &str {
-        &self.combined_description
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|source| source as &(dyn error::Error + 'static))
     }
+}
+
+impl<T> From<mpsc::error::SendError<T>> for Error {
+    fn from(err: mpsc::error::SendError<T>) -> Self {
+        Error::from_string(err.to_string(), ErrorType::ChannelError)
+    }
+}
 
-    fn source(self: &Self) -> Option<&(dyn error::Error + 'static)> {
-        Some(&self.error_type)
+impl From<oneshot::error::RecvError> for Error {
+    fn from(err: oneshot::error::RecvError) -> Self {
+        Error::from_source(err, ErrorType::ChannelError)
     }
 }
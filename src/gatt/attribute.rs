@@ -0,0 +1,83 @@
+use super::{
+    properties::{AttributePermission, CharacteristicProperty},
+    service::Service,
+};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttributeType {
+    PrimaryService,
+    SecondaryService,
+    /// Reference to an included service, whose uuid the attribute has
+    Include,
+    /// Characteristic value, its declaration is the attribute right before it
+    Characteristic,
+    Descriptor,
+}
+
+/// Entry of the attribute table of the registered services
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribute {
+    /// Handle assigned by the stack, `None` where the platform does not report handles.
+    /// BlueZ lists the fixed handles until the services are served
+    pub handle: Option<u16>,
+    pub attribute_type: AttributeType,
+    pub uuid: Uuid,
+    pub service: Uuid,
+    /// Characteristic of the attribute, set for characteristics and descriptors
+    pub characteristic: Option<Uuid>,
+    pub properties: Vec<CharacteristicProperty>,
+    pub permissions: Vec<AttributePermission>,
+}
+
+/// Attributes of the service in registration order, with their fixed handles
+pub(crate) fn service_attributes(service: &Service) -> Vec<Attribute> {
+    let mut attributes = vec![Attribute {
+        handle: service.handle,
+        attribute_type: match service.primary {
+            true => AttributeType::PrimaryService,
+            false => AttributeType::SecondaryService,
+        },
+        uuid: service.uuid,
+        service: service.uuid,
+        characteristic: None,
+        properties: Vec::new(),
+        permissions: Vec::new(),
+    }];
+
+    for include in service.includes.iter() {
+        attributes.push(Attribute {
+            handle: None,
+            attribute_type: AttributeType::Include,
+            uuid: *include,
+            service: service.uuid,
+            characteristic: None,
+            properties: Vec::new(),
+            permissions: Vec::new(),
+        });
+    }
+
+    for characteristic in service.characteristics.iter() {
+        attributes.push(Attribute {
+            handle: characteristic.handle,
+            attribute_type: AttributeType::Characteristic,
+            uuid: characteristic.uuid,
+            service: service.uuid,
+            characteristic: Some(characteristic.uuid),
+            properties: characteristic.properties.clone(),
+            permissions: characteristic.permissions.clone(),
+        });
+        for descriptor in characteristic.descriptors.iter() {
+            attributes.push(Attribute {
+                handle: descriptor.handle,
+                attribute_type: AttributeType::Descriptor,
+                uuid: descriptor.uuid,
+                service: service.uuid,
+                characteristic: Some(characteristic.uuid),
+                properties: descriptor.properties.clone(),
+                permissions: descriptor.permissions.clone(),
+            });
+        }
+    }
+    attributes
+}
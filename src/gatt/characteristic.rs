@@ -1,5 +1,5 @@
 use super::{
-    descriptor::Descriptor,
+    descriptor::{Descriptor, ExtendedProperties, EXTENDED_PROPERTIES_UUID},
     properties::{AttributePermission, CharacteristicProperty},
 };
 use uuid::Uuid;
@@ -7,6 +7,9 @@ use uuid::Uuid;
 #[derive(Debug, Clone)]
 pub struct Characteristic {
     pub uuid: Uuid,
+    /// Fixed handle of the characteristic value, assigned by the stack when `None`.
+    /// Only BlueZ supports fixed handles
+    pub handle: Option<u16>,
     pub properties: Vec<CharacteristicProperty>,
     pub permissions: Vec<AttributePermission>,
     pub value: Option<Vec<u8>>,
@@ -17,6 +20,7 @@ impl Default for Characteristic {
     fn default() -> Self {
         Characteristic {
             uuid: Uuid::nil(),
+            handle: None,
             properties: vec![
                 CharacteristicProperty::Read,
                 CharacteristicProperty::Write,
@@ -31,3 +35,32 @@ impl Default for Characteristic {
         }
     }
 }
+
+impl Characteristic {
+    /// Flags of the extended properties descriptor, if the characteristic has one
+    pub fn extended_properties(&self) -> Option<ExtendedProperties> {
+        self.descriptors
+            .iter()
+            .find(|descriptor| descriptor.uuid == EXTENDED_PROPERTIES_UUID)
+            .and_then(|descriptor| ExtendedProperties::from_bytes(descriptor.value.as_ref()?))
+    }
+}
+
+/// Characteristic qualified by the service it belongs to
+///
+/// Services are identified by their uuid, so the same characteristic uuid can be used in
+/// several services without their updates, values and subscriptions colliding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CharacteristicId {
+    pub service: Uuid,
+    pub characteristic: Uuid,
+}
+
+impl CharacteristicId {
+    pub fn new(service: Uuid, characteristic: Uuid) -> Self {
+        CharacteristicId {
+            service,
+            characteristic,
+        }
+    }
+}
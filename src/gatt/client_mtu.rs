@@ -0,0 +1,33 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// ATT MTU used by a client before it exchanges a larger one
+pub const DEFAULT_MTU: u16 = 23;
+
+/// MTU of every client, as last reported by the platform with its requests and subscriptions
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ClientMtus {
+    inner: Arc<Mutex<HashMap<String, u16>>>,
+}
+
+impl ClientMtus {
+    pub fn update(&self, client: &str, mtu: Option<u16>) {
+        if let (Some(mtu), Ok(mut inner)) = (mtu, self.inner.lock()) {
+            inner.insert(client.to_string(), mtu);
+        }
+    }
+
+    // CoreBluetooth does not report disconnections, there the MTUs are kept
+    #[cfg(any(not(any(target_os = "macos", target_os = "ios")), feature = "mock"))]
+    pub fn remove(&self, client: &str) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.remove(client);
+        }
+    }
+
+    pub fn get(&self, client: &str) -> Option<u16> {
+        self.inner.lock().ok()?.get(client).copied()
+    }
+}
@@ -1,9 +1,21 @@
 use super::properties::{AttributePermission, CharacteristicProperty};
 use uuid::Uuid;
 
+/// Characteristic Extended Properties, generated by the stack from its flags
+pub const EXTENDED_PROPERTIES_UUID: Uuid = Uuid::from_u128(0x00002900_0000_1000_8000_00805F9B34FB);
+pub const USER_DESCRIPTION_UUID: Uuid = Uuid::from_u128(0x00002901_0000_1000_8000_00805F9B34FB);
+/// Client Characteristic Configuration, added by the stacks to characteristics with notify or
+/// indicate and rejected in services
+pub const CLIENT_CONFIGURATION_UUID: Uuid = Uuid::from_u128(0x00002902_0000_1000_8000_00805F9B34FB);
+pub const PRESENTATION_FORMAT_UUID: Uuid = Uuid::from_u128(0x00002904_0000_1000_8000_00805F9B34FB);
+pub const VALID_RANGE_UUID: Uuid = Uuid::from_u128(0x00002906_0000_1000_8000_00805F9B34FB);
+
 #[derive(Debug, Clone)]
 pub struct Descriptor {
     pub uuid: Uuid,
+    /// Fixed attribute handle, assigned by the stack when `None`.
+    /// Only BlueZ supports fixed handles
+    pub handle: Option<u16>,
     pub properties: Vec<CharacteristicProperty>,
     pub permissions: Vec<AttributePermission>,
     pub value: Option<Vec<u8>>,
@@ -13,6 +25,7 @@ impl Default for Descriptor {
     fn default() -> Self {
         Descriptor {
             uuid: Uuid::nil(),
+            handle: None,
             properties: vec![
                 CharacteristicProperty::Read,
                 CharacteristicProperty::Write,
@@ -26,3 +39,95 @@ impl Default for Descriptor {
         }
     }
 }
+
+impl Descriptor {
+    /// Human readable name of the characteristic
+    pub fn user_description(description: &str) -> Self {
+        Self::read_only(USER_DESCRIPTION_UUID, description.as_bytes().to_vec())
+    }
+
+    pub fn presentation_format(format: PresentationFormat) -> Self {
+        Self::read_only(PRESENTATION_FORMAT_UUID, format.to_bytes())
+    }
+
+    /// Extended properties of the characteristic, which the stacks generate from its flags.
+    /// BlueZ does not support writable auxiliaries, and CoreBluetooth neither flag
+    pub fn extended_properties(properties: ExtendedProperties) -> Self {
+        Self::read_only(EXTENDED_PROPERTIES_UUID, properties.to_bytes())
+    }
+
+    /// Inclusive range of the characteristic value, with both bounds encoded like the value
+    pub fn valid_range(lower: &[u8], upper: &[u8]) -> Self {
+        Self::read_only(VALID_RANGE_UUID, [lower, upper].concat())
+    }
+
+    fn read_only(uuid: Uuid, value: Vec<u8>) -> Self {
+        Descriptor {
+            uuid,
+            properties: vec![CharacteristicProperty::Read],
+            permissions: vec![AttributePermission::Readable],
+            value: Some(value),
+            ..Default::default()
+        }
+    }
+}
+
+/// Content of a Characteristic Presentation Format descriptor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PresentationFormat {
+    /// Format of the value, e.g. `0x04` for an unsigned 8-bit integer
+    pub format: u8,
+    /// Base 10 exponent applied to integer values
+    pub exponent: i8,
+    /// Unit uuid, e.g. `0x27AD` for percentage
+    pub unit: u16,
+    /// Namespace of `description`, `0x01` for the Bluetooth SIG
+    pub namespace: u8,
+    pub description: u16,
+}
+
+impl PresentationFormat {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.format, self.exponent as u8];
+        bytes.extend_from_slice(&self.unit.to_le_bytes());
+        bytes.push(self.namespace);
+        bytes.extend_from_slice(&self.description.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; 7] = bytes.try_into().ok()?;
+        Some(PresentationFormat {
+            format: bytes[0],
+            exponent: bytes[1] as i8,
+            unit: u16::from_le_bytes([bytes[2], bytes[3]]),
+            namespace: bytes[4],
+            description: u16::from_le_bytes([bytes[5], bytes[6]]),
+        })
+    }
+}
+
+/// Content of a Characteristic Extended Properties descriptor
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExtendedProperties {
+    /// Writes can be queued and executed at once
+    pub reliable_write: bool,
+    /// The user description descriptor is writable
+    pub writable_auxiliaries: bool,
+}
+
+impl ExtendedProperties {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let flags = self.reliable_write as u16 | (self.writable_auxiliaries as u16) << 1;
+        flags.to_le_bytes().to_vec()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; 2] = bytes.try_into().ok()?;
+        let flags = u16::from_le_bytes(*bytes);
+        Some(ExtendedProperties {
+            reliable_write: flags & 0x01 != 0,
+            writable_auxiliaries: flags & 0x02 != 0,
+        })
+    }
+}
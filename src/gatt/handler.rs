@@ -0,0 +1,333 @@
+use super::peripheral_event::{
+    AddressType, DescriptorRequest, PeripheralEvent, PeripheralRequest, ReadRequestResponse,
+    RequestResponse, WriteRequestResponse,
+};
+use async_trait::async_trait;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::mpsc::{self, Receiver, UnboundedSender};
+use uuid::Uuid;
+
+/// Handles the requests of the services or characteristics it is registered for
+///
+/// Requests a handler does not implement are answered with `RequestNotSupported`.
+///
+/// A handler is called with one event at a time, in the order the peripheral sent them, and
+/// the next call only starts once the previous one returned. So writes, the cancellation of
+/// prepared writes and subscription changes of a client are seen in the order they happened.
+/// Different handlers run concurrently, a handler registered for several attributes still
+/// gets all of their events through the same sequence.
+#[async_trait]
+pub trait GattHandler: Send + Sync {
+    async fn on_read(&self, _request: PeripheralRequest, _offset: u64) -> ReadRequestResponse {
+        ReadRequestResponse {
+            value: Vec::new(),
+            response: RequestResponse::RequestNotSupported,
+        }
+    }
+
+    async fn on_write(
+        &self,
+        _request: PeripheralRequest,
+        _value: Vec<u8>,
+        _offset: u64,
+    ) -> WriteRequestResponse {
+        WriteRequestResponse {
+            response: RequestResponse::RequestNotSupported,
+        }
+    }
+
+    /// Read of a descriptor without a static value
+    async fn on_descriptor_read(
+        &self,
+        _request: DescriptorRequest,
+        _offset: u64,
+    ) -> ReadRequestResponse {
+        ReadRequestResponse {
+            value: Vec::new(),
+            response: RequestResponse::RequestNotSupported,
+        }
+    }
+
+    async fn on_descriptor_write(
+        &self,
+        _request: DescriptorRequest,
+        _value: Vec<u8>,
+        _offset: u64,
+    ) -> WriteRequestResponse {
+        WriteRequestResponse {
+            response: RequestResponse::RequestNotSupported,
+        }
+    }
+
+    async fn on_subscribe(&self, _request: PeripheralRequest, _subscribed: bool) {}
+
+    async fn on_prepared_write_cancelled(&self, _request: PeripheralRequest) {}
+
+    /// Request answered by the crate, see `PeripheralEvent::RequestUnanswered`
+    async fn on_request_unanswered(
+        &self,
+        _request: PeripheralRequest,
+        _descriptor: Option<Uuid>,
+        _timed_out: bool,
+        _response: RequestResponse,
+    ) {
+    }
+
+    /// Called on every registered handler
+    async fn on_state_change(&self, _is_powered: bool) {}
+
+    /// Called on every registered handler
+    async fn on_client_connected(&self, _client: String, _address_type: Option<AddressType>) {}
+
+    /// Called on every registered handler
+    async fn on_client_disconnected(&self, _client: String, _address_type: Option<AddressType>) {}
+}
+
+/// Dispatches `PeripheralEvent`s to the `GattHandler` registered for their characteristic,
+/// or else for their service
+///
+/// Each handler runs in a task of its own, see `GattHandler` for the order of the calls.
+/// Clones share these tasks.
+#[derive(Default, Clone)]
+pub struct GattHandlers {
+    services: HashMap<Uuid, Arc<dyn GattHandler>>,
+    characteristics: HashMap<(Uuid, Uuid), Arc<dyn GattHandler>>,
+    queues: Arc<Mutex<Vec<HandlerQueue>>>,
+}
+
+// Events waiting for the task of one handler
+struct HandlerQueue {
+    handler: Arc<dyn GattHandler>,
+    sender: UnboundedSender<PeripheralEvent>,
+}
+
+impl GattHandlers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle the characteristics of `service` without a handler of their own
+    pub fn add_service_handler(&mut self, service: Uuid, handler: Arc<dyn GattHandler>) {
+        self.services.insert(service, handler);
+    }
+
+    pub fn add_characteristic_handler(
+        &mut self,
+        service: Uuid,
+        characteristic: Uuid,
+        handler: Arc<dyn GattHandler>,
+    ) {
+        self.characteristics
+            .insert((service, characteristic), handler);
+    }
+
+    /// Dispatch events until the peripheral is dropped.
+    /// Events no handler takes are answered with `RequestNotSupported` or ignored.
+    pub async fn run(self, mut receiver_rx: Receiver<PeripheralEvent>) {
+        while let Some(event) = receiver_rx.recv().await {
+            if let Some(event) = self.handle(event) {
+                reject(event);
+            }
+        }
+    }
+
+    /// Dispatch a single event, returning it when no handler takes it
+    pub fn handle(&self, event: PeripheralEvent) -> Option<PeripheralEvent> {
+        match event {
+            PeripheralEvent::StateUpdate { is_powered } => {
+                for handler in self.all_handlers() {
+                    self.enqueue(&handler, PeripheralEvent::StateUpdate { is_powered });
+                }
+                None
+            }
+            PeripheralEvent::ClientConnected {
+                client,
+                address_type,
+            } => {
+                for handler in self.all_handlers() {
+                    let event = PeripheralEvent::ClientConnected {
+                        client: client.clone(),
+                        address_type,
+                    };
+                    self.enqueue(&handler, event);
+                }
+                None
+            }
+            PeripheralEvent::ClientDisconnected {
+                client,
+                address_type,
+            } => {
+                for handler in self.all_handlers() {
+                    let event = PeripheralEvent::ClientDisconnected {
+                        client: client.clone(),
+                        address_type,
+                    };
+                    self.enqueue(&handler, event);
+                }
+                None
+            }
+            event => {
+                let handler = attribute(&event).and_then(|(service, characteristic)| {
+                    self.find_handler(service, characteristic)
+                });
+                let Some(handler) = handler else {
+                    return Some(event);
+                };
+                self.enqueue(&handler, event);
+                None
+            }
+        }
+    }
+
+    // Events go through a queue per handler, so each handler sees them one at a time in the
+    // order they arrived, while a slow handler does not hold up the others
+    fn enqueue(&self, handler: &Arc<dyn GattHandler>, event: PeripheralEvent) {
+        let Ok(mut queues) = self.queues.lock() else {
+            return;
+        };
+        let queue = match queues.iter().position(|q| Arc::ptr_eq(&q.handler, handler)) {
+            Some(index) => &queues[index],
+            None => {
+                let (sender, mut receiver) = mpsc::unbounded_channel::<PeripheralEvent>();
+                let worker = handler.clone();
+                tokio::spawn(async move {
+                    while let Some(event) = receiver.recv().await {
+                        dispatch(worker.as_ref(), event).await;
+                    }
+                });
+                queues.push(HandlerQueue {
+                    handler: handler.clone(),
+                    sender,
+                });
+                &queues[queues.len() - 1]
+            }
+        };
+        if let Err(err) = queue.sender.send(event) {
+            reject(err.0);
+        }
+    }
+
+    fn find_handler(&self, service: Uuid, characteristic: Uuid) -> Option<Arc<dyn GattHandler>> {
+        self.characteristics
+            .get(&(service, characteristic))
+            .or_else(|| self.services.get(&service))
+            .cloned()
+    }
+
+    // Each handler once, even when registered for several attributes
+    fn all_handlers(&self) -> Vec<Arc<dyn GattHandler>> {
+        let mut handlers: Vec<Arc<dyn GattHandler>> = Vec::new();
+        for handler in self.services.values().chain(self.characteristics.values()) {
+            if !handlers.iter().any(|h| Arc::ptr_eq(h, handler)) {
+                handlers.push(handler.clone());
+            }
+        }
+        handlers
+    }
+}
+
+// Characteristic whose handler takes the event
+fn attribute(event: &PeripheralEvent) -> Option<(Uuid, Uuid)> {
+    let (service, characteristic) = match event {
+        PeripheralEvent::CharacteristicSubscriptionUpdate { request, .. }
+        | PeripheralEvent::ReadRequest { request, .. }
+        | PeripheralEvent::WriteRequest { request, .. }
+        | PeripheralEvent::PreparedWriteCancelled { request }
+        | PeripheralEvent::RequestUnanswered { request, .. } => {
+            (request.service, request.characteristic)
+        }
+        PeripheralEvent::DescriptorReadRequest { request, .. }
+        | PeripheralEvent::DescriptorWriteRequest { request, .. } => {
+            (request.service, request.characteristic)
+        }
+        _ => return None,
+    };
+    Some((service, characteristic))
+}
+
+async fn dispatch(handler: &dyn GattHandler, event: PeripheralEvent) {
+    match event {
+        PeripheralEvent::StateUpdate { is_powered } => handler.on_state_change(is_powered).await,
+        PeripheralEvent::ClientConnected {
+            client,
+            address_type,
+        } => handler.on_client_connected(client, address_type).await,
+        PeripheralEvent::ClientDisconnected {
+            client,
+            address_type,
+        } => handler.on_client_disconnected(client, address_type).await,
+        PeripheralEvent::CharacteristicSubscriptionUpdate {
+            request,
+            subscribed,
+        } => handler.on_subscribe(request, subscribed).await,
+        PeripheralEvent::ReadRequest {
+            request,
+            offset,
+            responder,
+        } => {
+            let response = handler.on_read(request, offset).await;
+            let _ = responder.send(response);
+        }
+        PeripheralEvent::WriteRequest {
+            request,
+            value,
+            offset,
+            responder,
+        } => {
+            let response = handler.on_write(request, value, offset).await;
+            let _ = responder.send(response);
+        }
+        PeripheralEvent::DescriptorReadRequest {
+            request,
+            offset,
+            responder,
+        } => {
+            let response = handler.on_descriptor_read(request, offset).await;
+            let _ = responder.send(response);
+        }
+        PeripheralEvent::DescriptorWriteRequest {
+            request,
+            value,
+            offset,
+            responder,
+        } => {
+            let response = handler.on_descriptor_write(request, value, offset).await;
+            let _ = responder.send(response);
+        }
+        PeripheralEvent::PreparedWriteCancelled { request } => {
+            handler.on_prepared_write_cancelled(request).await
+        }
+        PeripheralEvent::RequestUnanswered {
+            request,
+            descriptor,
+            timed_out,
+            response,
+        } => {
+            handler
+                .on_request_unanswered(request, descriptor, timed_out, response)
+                .await
+        }
+    }
+}
+
+fn reject(event: PeripheralEvent) {
+    match event {
+        PeripheralEvent::ReadRequest { responder, .. }
+        | PeripheralEvent::DescriptorReadRequest { responder, .. } => {
+            let _ = responder.send(ReadRequestResponse {
+                value: Vec::new(),
+                response: RequestResponse::RequestNotSupported,
+            });
+        }
+        PeripheralEvent::WriteRequest { responder, .. }
+        | PeripheralEvent::DescriptorWriteRequest { responder, .. } => {
+            let _ = responder.send(WriteRequestResponse {
+                response: RequestResponse::RequestNotSupported,
+            });
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,123 @@
+use super::{characteristic::CharacteristicId, peripheral_event::RequestResponse};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// Long reads answered by the crate once enabled
+///
+/// While enabled, a `ReadRequest` is only sent for the first read of a value, always with
+/// offset 0, and the application returns the full value. The value is kept per client and
+/// characteristic, and the following Read Blob requests of the long read are sliced from it.
+/// Reads past the end of the value fail with `InvalidOffset`. The value is dropped once a read
+/// returns less than fits into a response of MTU - 1 bytes, or when the client disconnects on
+/// BlueZ, Windows and the mock backend. CoreBluetooth does not report disconnections.
+#[derive(Debug, Clone, Default)]
+pub struct LongReads {
+    inner: Arc<Mutex<LongReadsInner>>,
+}
+
+#[derive(Debug, Default)]
+struct LongReadsInner {
+    enabled: bool,
+    values: HashMap<(String, CharacteristicId), Vec<u8>>,
+}
+
+impl LongReads {
+    pub fn set_enabled(&self, enabled: bool) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.enabled = enabled;
+            if !enabled {
+                inner.values.clear();
+            }
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.lock().is_ok_and(|inner| inner.enabled)
+    }
+
+    /// Offset to hand to the application in the `ReadRequest`
+    pub(crate) fn request_offset(&self, offset: u64) -> u64 {
+        if self.is_enabled() {
+            0
+        } else {
+            offset
+        }
+    }
+
+    /// Continue a long read from the cached value, `None` when it has to go to the application
+    pub(crate) fn read(
+        &self,
+        client: &str,
+        characteristic: CharacteristicId,
+        offset: u64,
+        mtu: Option<u16>,
+    ) -> Option<Result<Vec<u8>, RequestResponse>> {
+        if offset == 0 {
+            return None;
+        }
+        let mut inner = self.inner.lock().ok()?;
+        if !inner.enabled {
+            return None;
+        }
+        let key = (client.to_string(), characteristic);
+        let result = slice_value(inner.values.get(&key)?, offset);
+        if result
+            .as_ref()
+            .is_none_or(|value| is_last_chunk(value, mtu))
+        {
+            inner.values.remove(&key);
+        }
+        Some(result.ok_or(RequestResponse::InvalidOffset))
+    }
+
+    /// Keep the full value returned by the application and answer the read at `offset`
+    pub(crate) fn respond(
+        &self,
+        client: &str,
+        characteristic: CharacteristicId,
+        offset: u64,
+        mtu: Option<u16>,
+        value: Vec<u8>,
+    ) -> Result<Vec<u8>, RequestResponse> {
+        let Ok(mut inner) = self.inner.lock() else {
+            return Ok(value);
+        };
+        if !inner.enabled {
+            return Ok(value);
+        }
+        let result = slice_value(&value, offset).ok_or(RequestResponse::InvalidOffset);
+        let key = (client.to_string(), characteristic);
+        if result
+            .as_ref()
+            .is_ok_and(|chunk| !is_last_chunk(chunk, mtu))
+        {
+            inner.values.insert(key, value);
+        } else {
+            inner.values.remove(&key);
+        }
+        result
+    }
+
+    /// Drop the values of a disconnected client
+    #[cfg(any(not(any(target_os = "macos", target_os = "ios")), feature = "mock"))]
+    pub(crate) fn remove_client(&self, client: &str) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.values.retain(|(c, _), _| c != client);
+        }
+    }
+}
+
+// Nothing is left to read once a chunk does not fill a read response of MTU - 1 bytes.
+// Without the MTU the long read only ends at the end of the value.
+fn is_last_chunk(chunk: &[u8], mtu: Option<u16>) -> bool {
+    match mtu {
+        Some(mtu) => chunk.len() < usize::from(mtu).saturating_sub(1),
+        None => chunk.is_empty(),
+    }
+}
+
+fn slice_value(value: &[u8], offset: u64) -> Option<Vec<u8>> {
+    value.get(offset as usize..).map(|value| value.to_vec())
+}
@@ -1,5 +1,12 @@
+pub mod attribute;
 pub mod characteristic;
+pub mod client_mtu;
 pub mod descriptor;
+pub mod handler;
+pub mod long_read;
 pub mod peripheral_event;
+pub mod prepared_write;
 pub mod properties;
+pub mod response_timeout;
 pub mod service;
+pub mod value_store;
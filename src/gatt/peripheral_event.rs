@@ -1,3 +1,4 @@
+use super::characteristic::CharacteristicId;
 use tokio::sync::oneshot;
 use uuid::Uuid;
 
@@ -6,6 +7,15 @@ pub enum PeripheralEvent {
     StateUpdate {
         is_powered: bool,
     },
+    /// Central connected to the adapter, only reported by BlueZ and the mock backend
+    ClientConnected {
+        client: String,
+        address_type: Option<AddressType>,
+    },
+    ClientDisconnected {
+        client: String,
+        address_type: Option<AddressType>,
+    },
     CharacteristicSubscriptionUpdate {
         request: PeripheralRequest,
         subscribed: bool,
@@ -21,13 +31,85 @@ pub enum PeripheralEvent {
         offset: u64,
         responder: oneshot::Sender<WriteRequestResponse>,
     },
+    /// Read of a descriptor without a static value
+    DescriptorReadRequest {
+        request: DescriptorRequest,
+        offset: u64,
+        responder: oneshot::Sender<ReadRequestResponse>,
+    },
+    DescriptorWriteRequest {
+        request: DescriptorRequest,
+        value: Vec<u8>,
+        offset: u64,
+        responder: oneshot::Sender<WriteRequestResponse>,
+    },
+    /// Queue of prepared writes dropped by the client before executing it
+    PreparedWriteCancelled {
+        request: PeripheralRequest,
+    },
+    /// Request whose responder was dropped, or left unanswered past the deadline of the
+    /// `ResponseTimeout`, and answered with `response` by the crate
+    RequestUnanswered {
+        request: PeripheralRequest,
+        /// Set for descriptor requests
+        descriptor: Option<Uuid>,
+        timed_out: bool,
+        response: RequestResponse,
+    },
 }
 
 #[derive(Debug, Clone)]
 pub struct PeripheralRequest {
+    /// Empty for the subscriptions of BlueZ indicate-only characteristics, which are shared
+    pub client: String,
+    pub service: Uuid,
+    pub characteristic: Uuid,
+    /// ATT MTU of the connection, `None` where the platform does not report it
+    pub mtu: Option<u16>,
+    pub link: Option<LinkType>,
+}
+
+impl PeripheralRequest {
+    /// Characteristic the request is for, e.g. to update it in response
+    pub fn characteristic_id(&self) -> CharacteristicId {
+        CharacteristicId::new(self.service, self.characteristic)
+    }
+
+    /// Longest value a single notification or indication to the client can carry, MTU - 3
+    pub fn max_notification_length(&self) -> Option<usize> {
+        self.mtu.map(|mtu| usize::from(mtu).saturating_sub(3))
+    }
+
+    /// Longest part of a value a single read response to the client can carry, MTU - 1
+    pub fn max_read_length(&self) -> Option<usize> {
+        self.mtu.map(|mtu| usize::from(mtu).saturating_sub(1))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DescriptorRequest {
     pub client: String,
     pub service: Uuid,
     pub characteristic: Uuid,
+    pub descriptor: Uuid,
+    /// ATT MTU of the connection, `None` where the platform does not report it
+    pub mtu: Option<u16>,
+    pub link: Option<LinkType>,
+}
+
+/// Transport the client is connected over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkType {
+    Le,
+    BrEdr,
+}
+
+/// Type of the address a client is identified by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressType {
+    LePublic,
+    LeRandom,
+    BrEdr,
 }
 
 #[derive(Debug)]
@@ -41,11 +123,89 @@ pub struct WriteRequestResponse {
     pub response: RequestResponse,
 }
 
-#[derive(Debug, PartialEq)]
+/// Outcome of a request, answered to the client as the ATT error of the same name
+///
+/// CoreBluetooth, Windows and the mock backend answer every code as is. BlueZ only knows a
+/// handful of errors, see `Readme.md` for how the others are answered there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RequestResponse {
     Success,
     InvalidHandle,
+    ReadNotPermitted,
+    WriteNotPermitted,
+    InvalidPdu,
+    InsufficientAuthentication,
     RequestNotSupported,
     InvalidOffset,
+    InsufficientAuthorization,
+    PrepareQueueFull,
+    AttributeNotFound,
+    AttributeNotLong,
+    InsufficientEncryptionKeySize,
+    InvalidAttributeValueLength,
     UnlikelyError,
+    InsufficientEncryption,
+    UnsupportedGroupType,
+    InsufficientResources,
+    ValueNotAllowed,
+    /// Error defined by the application's profile, in the range `0x80..=0x9F`.
+    /// Codes outside of it are answered as `UnlikelyError`
+    ApplicationError(u8),
+    WriteRequestRejected,
+    CccdImproperlyConfigured,
+    ProcedureAlreadyInProgress,
+    OutOfRange,
+}
+
+impl RequestResponse {
+    /// ATT error code, `0x00` for `Success`
+    pub fn code(&self) -> u8 {
+        match self {
+            RequestResponse::Success => 0x00,
+            RequestResponse::InvalidHandle => 0x01,
+            RequestResponse::ReadNotPermitted => 0x02,
+            RequestResponse::WriteNotPermitted => 0x03,
+            RequestResponse::InvalidPdu => 0x04,
+            RequestResponse::InsufficientAuthentication => 0x05,
+            RequestResponse::RequestNotSupported => 0x06,
+            RequestResponse::InvalidOffset => 0x07,
+            RequestResponse::InsufficientAuthorization => 0x08,
+            RequestResponse::PrepareQueueFull => 0x09,
+            RequestResponse::AttributeNotFound => 0x0A,
+            RequestResponse::AttributeNotLong => 0x0B,
+            RequestResponse::InsufficientEncryptionKeySize => 0x0C,
+            RequestResponse::InvalidAttributeValueLength => 0x0D,
+            RequestResponse::UnlikelyError => 0x0E,
+            RequestResponse::InsufficientEncryption => 0x0F,
+            RequestResponse::UnsupportedGroupType => 0x10,
+            RequestResponse::InsufficientResources => 0x11,
+            RequestResponse::ValueNotAllowed => 0x13,
+            RequestResponse::ApplicationError(code @ 0x80..=0x9F) => *code,
+            RequestResponse::ApplicationError(_) => 0x0E,
+            RequestResponse::WriteRequestRejected => 0xFC,
+            RequestResponse::CccdImproperlyConfigured => 0xFD,
+            RequestResponse::ProcedureAlreadyInProgress => 0xFE,
+            RequestResponse::OutOfRange => 0xFF,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NotificationResult {
+    pub client: String,
+    pub status: NotificationStatus,
+}
+
+/// Outcome of a characteristic update for a single client
+#[derive(Debug, Clone, PartialEq)]
+pub enum NotificationStatus {
+    /// Handed over to the Bluetooth stack
+    Sent,
+    /// Indication acknowledged by the client, only reported where the platform exposes it
+    Confirmed,
+    /// Transmit queue of the stack is full, the update can be retried later
+    QueueFull,
+    /// Client is no longer connected or subscribed
+    ClientGone,
+    Failed(String),
 }
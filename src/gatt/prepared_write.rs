@@ -0,0 +1,177 @@
+use super::peripheral_event::RequestResponse;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+/// Largest attribute value allowed by the ATT protocol
+pub const MAX_ATTRIBUTE_LENGTH: usize = 512;
+
+/// Prepared (long and reliable) writes reassembled by the crate once enabled
+///
+/// While enabled, the fragments queued by a client are buffered per characteristic and
+/// delivered as a single `WriteRequest` at offset 0 when the client executes the queue.
+/// A cancelled queue is reported with `PreparedWriteCancelled` where the platform tells about it.
+/// Fragments that would grow a value past `max_length` fail with `InvalidAttributeValueLength`.
+///
+/// Only CoreBluetooth and the mock backend hand the executed queue over at once. BlueZ
+/// executes it itself, fragment by fragment, and reassembles only with `set_best_effort`.
+/// Windows does not reassemble at all, there only the length is checked.
+#[derive(Debug, Clone, Default)]
+pub struct PreparedWrites {
+    inner: Arc<Mutex<PreparedWritesInner>>,
+}
+
+#[derive(Debug)]
+struct PreparedWritesInner {
+    enabled: bool,
+    best_effort: bool,
+    max_length: usize,
+    queues: HashMap<String, PreparedQueue>,
+}
+
+#[derive(Debug)]
+#[cfg_attr(all(target_os = "windows", not(feature = "mock")), allow(dead_code))]
+struct PreparedQueue {
+    writes: Vec<PreparedWrite>,
+    updated: Instant,
+}
+
+impl Default for PreparedWritesInner {
+    fn default() -> Self {
+        PreparedWritesInner {
+            enabled: false,
+            best_effort: false,
+            max_length: MAX_ATTRIBUTE_LENGTH,
+            queues: HashMap::new(),
+        }
+    }
+}
+
+/// Value reassembled from the prepared writes of a client to one characteristic
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PreparedWrite {
+    pub service: Uuid,
+    pub characteristic: Uuid,
+    pub value: Vec<u8>,
+}
+
+impl PreparedWrites {
+    pub fn set_enabled(&self, enabled: bool) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.enabled = enabled;
+            if !enabled {
+                inner.queues.clear();
+            }
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.lock().is_ok_and(|inner| inner.enabled)
+    }
+
+    /// Reassemble on BlueZ as well, where the end of a queue is only guessed from a pause
+    /// in the fragments. Every fragment is acknowledged to the client before the value is
+    /// delivered, so rejecting it is only logged, queues executed in quick succession are
+    /// merged and cancelled queues go unnoticed.
+    pub fn set_best_effort(&self, best_effort: bool) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.best_effort = best_effort;
+        }
+    }
+
+    pub fn is_best_effort(&self) -> bool {
+        self.inner.lock().is_ok_and(|inner| inner.best_effort)
+    }
+
+    pub fn set_max_length(&self, max_length: usize) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.max_length = max_length;
+        }
+    }
+
+    pub fn max_length(&self) -> usize {
+        self.inner
+            .lock()
+            .map(|inner| inner.max_length)
+            .unwrap_or(MAX_ATTRIBUTE_LENGTH)
+    }
+}
+
+// Windows executes prepared writes itself, the queues are only filled on the other backends
+#[cfg_attr(all(target_os = "windows", not(feature = "mock")), allow(dead_code))]
+impl PreparedWrites {
+    /// Buffer a fragment queued by `client`
+    pub(crate) fn prepare(
+        &self,
+        client: &str,
+        service: Uuid,
+        characteristic: Uuid,
+        offset: u64,
+        value: &[u8],
+    ) -> Result<(), RequestResponse> {
+        let mut inner = self
+            .inner
+            .lock()
+            .map_err(|_| RequestResponse::UnlikelyError)?;
+        let max_length = inner.max_length;
+        let queue = inner
+            .queues
+            .entry(client.to_string())
+            .or_insert_with(|| PreparedQueue {
+                writes: Vec::new(),
+                updated: Instant::now(),
+            });
+        queue.updated = Instant::now();
+        let queue = &mut queue.writes;
+        let index = match queue
+            .iter()
+            .position(|write| write.service == service && write.characteristic == characteristic)
+        {
+            Some(index) => index,
+            None => {
+                queue.push(PreparedWrite {
+                    service,
+                    characteristic,
+                    value: Vec::new(),
+                });
+                queue.len() - 1
+            }
+        };
+
+        let buffered = &mut queue[index].value;
+        let offset = offset as usize;
+        if offset > buffered.len() {
+            return Err(RequestResponse::InvalidOffset);
+        }
+        if offset + value.len() > max_length {
+            return Err(RequestResponse::InvalidAttributeValueLength);
+        }
+        buffered.truncate(offset);
+        buffered.extend_from_slice(value);
+        Ok(())
+    }
+
+    /// Take the reassembled values of `client`, in the order they were first prepared.
+    /// Nothing is taken while fragments were prepared within `idle`, for stacks that execute
+    /// the queue themselves without telling when it ends.
+    pub(crate) fn take(&self, client: &str, idle: Duration) -> Vec<PreparedWrite> {
+        let Ok(mut inner) = self.inner.lock() else {
+            return Vec::new();
+        };
+        if inner
+            .queues
+            .get(client)
+            .is_none_or(|queue| queue.updated.elapsed() < idle)
+        {
+            return Vec::new();
+        }
+        inner
+            .queues
+            .remove(client)
+            .map(|queue| queue.writes)
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,130 @@
+use super::peripheral_event::{PeripheralEvent, PeripheralRequest, RequestResponse};
+use once_cell::sync::Lazy;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    runtime::{self, Runtime},
+    sync::{mpsc::Sender, oneshot},
+};
+use uuid::Uuid;
+
+// CoreBluetooth and Windows call back on threads of their own, outside of any runtime.
+// Responses are awaited here instead, so those threads are not held up while waiting.
+static RESPONSE_RUNTIME: Lazy<Runtime> = Lazy::new(|| {
+    runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("ble-peripheral-responses")
+        .enable_time()
+        .build()
+        .expect("Failed to create the response runtime")
+});
+
+/// Runtime for waiting on responses from threads outside of a runtime
+pub(crate) fn response_runtime() -> &'static Runtime {
+    &RESPONSE_RUNTIME
+}
+
+/// Deadline for the application to answer read and write requests
+///
+/// Requests left unanswered past the deadline, or whose responder was dropped, are answered
+/// with `response` and reported with `RequestUnanswered`, so a stuck handler cannot hold up
+/// the GATT server. Without a deadline the crate waits until the application answers.
+#[derive(Debug, Clone, Default)]
+pub struct ResponseTimeout {
+    inner: Arc<Mutex<ResponseTimeoutInner>>,
+}
+
+#[derive(Debug)]
+struct ResponseTimeoutInner {
+    timeout: Option<Duration>,
+    response: RequestResponse,
+}
+
+impl Default for ResponseTimeoutInner {
+    fn default() -> Self {
+        ResponseTimeoutInner {
+            timeout: None,
+            response: RequestResponse::UnlikelyError,
+        }
+    }
+}
+
+impl ResponseTimeout {
+    pub fn set_timeout(&self, timeout: Option<Duration>) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.timeout = timeout;
+        }
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.inner.lock().ok().and_then(|inner| inner.timeout)
+    }
+
+    /// Error answered to the client for unanswered requests, `UnlikelyError` by default
+    pub fn set_response(&self, response: RequestResponse) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.response = response;
+        }
+    }
+
+    pub fn response(&self) -> RequestResponse {
+        self.inner
+            .lock()
+            .map(|inner| inner.response)
+            .unwrap_or(RequestResponse::UnlikelyError)
+    }
+
+    /// Wait for the application to answer `request`, or fail with the configured response
+    pub(crate) async fn wait<T: Send + 'static>(
+        &self,
+        sender_tx: &Sender<PeripheralEvent>,
+        request: PeripheralRequest,
+        descriptor: Option<Uuid>,
+        receiver: oneshot::Receiver<T>,
+    ) -> Result<T, RequestResponse> {
+        let timed_out = match receive(receiver, self.timeout()).await {
+            Ok(result) => return Ok(result),
+            Err(timed_out) => timed_out,
+        };
+
+        let response = self.response();
+        // The application may be what stopped draining the channel, never wait on it here
+        if let Err(err) = sender_tx.try_send(PeripheralEvent::RequestUnanswered {
+            request,
+            descriptor,
+            timed_out,
+            response,
+        }) {
+            log::error!("Error sending request unanswered event: {err:?}");
+        }
+        Err(response)
+    }
+}
+
+/// Receive the response, failing with whether the deadline passed or the responder was dropped
+async fn receive<T: Send + 'static>(
+    receiver: oneshot::Receiver<T>,
+    timeout: Option<Duration>,
+) -> Result<T, bool> {
+    let Some(timeout) = timeout else {
+        return receiver.await.map_err(|_| false);
+    };
+    let result = if tokio::runtime::Handle::try_current().is_ok() {
+        tokio::time::timeout(timeout, receiver).await
+    } else {
+        // The timer of a runtime is needed for the deadline, the task ends with the response
+        match response_runtime()
+            .spawn(tokio::time::timeout(timeout, receiver))
+            .await
+        {
+            Ok(result) => result,
+            Err(_) => return Err(false),
+        }
+    };
+    match result {
+        Ok(result) => result.map_err(|_| false),
+        Err(_) => Err(true),
+    }
+}
@@ -1,10 +1,18 @@
-use super::characteristic::Characteristic;
+use super::{characteristic::Characteristic, descriptor::CLIENT_CONFIGURATION_UUID};
+use crate::error::{Error, ErrorType};
 use uuid::Uuid;
 
 #[derive(Debug, Clone)]
 pub struct Service {
     pub uuid: Uuid,
+    /// Fixed attribute handle, assigned by the stack when `None`.
+    /// Only BlueZ supports fixed handles
+    pub handle: Option<u16>,
+    /// Secondary services are only found by clients through the services including them
     pub primary: bool,
+    /// Services included by this one, which must be added before it.
+    /// BlueZ and Windows cannot register includes and reject services with any
+    pub includes: Vec<Uuid>,
     pub characteristics: Vec<Characteristic>,
 }
 
@@ -12,8 +20,53 @@ impl Default for Service {
     fn default() -> Self {
         Service {
             uuid: Uuid::nil(),
+            handle: None,
             primary: true,
+            includes: Vec::new(),
             characteristics: Vec::new(),
         }
     }
 }
+
+impl Service {
+    // Characteristics are identified by their service uuid, so it has to be unique among the
+    // added `services`. Every stack adds the client characteristic configuration descriptor of
+    // characteristics with notify or indicate itself, and tracks subscriptions through it.
+    pub(crate) fn validate(&self, services: &[Service]) -> Result<(), Error> {
+        if services.iter().any(|service| service.uuid == self.uuid) {
+            return Err(Error::from_string(
+                format!("Service {} is already added", self.uuid),
+                ErrorType::InvalidService,
+            ));
+        }
+        for (index, characteristic) in self.characteristics.iter().enumerate() {
+            if self.characteristics[..index]
+                .iter()
+                .any(|other| other.uuid == characteristic.uuid)
+            {
+                return Err(Error::from_string(
+                    format!(
+                        "Characteristic {} is declared twice in service {}",
+                        characteristic.uuid, self.uuid
+                    ),
+                    ErrorType::InvalidService,
+                ));
+            }
+            if characteristic
+                .descriptors
+                .iter()
+                .any(|descriptor| descriptor.uuid == CLIENT_CONFIGURATION_UUID)
+            {
+                return Err(Error::from_string(
+                    format!(
+                        "Characteristic {} declares a client characteristic configuration \
+                         descriptor, it is added by the crate",
+                        characteristic.uuid
+                    ),
+                    ErrorType::InvalidService,
+                ));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use super::{characteristic::CharacteristicId, peripheral_event::RequestResponse};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// Current values of characteristics, kept by the crate once enabled
+///
+/// While enabled, reads of characteristics with a stored value are answered from the store
+/// without a `ReadRequest`, writes accepted by the application are applied to it, and
+/// `update_characteristic` stores the new value before notifying. Writes at an offset past the
+/// end of the stored value fail with `InvalidOffset`.
+/// Characteristics without a stored value keep going through the events.
+#[derive(Debug, Clone, Default)]
+pub struct ValueStore {
+    inner: Arc<Mutex<ValueStoreInner>>,
+}
+
+#[derive(Debug, Default)]
+struct ValueStoreInner {
+    enabled: bool,
+    values: HashMap<CharacteristicId, Vec<u8>>,
+}
+
+impl ValueStore {
+    pub fn set_enabled(&self, enabled: bool) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.enabled = enabled;
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.lock().is_ok_and(|inner| inner.enabled)
+    }
+
+    pub fn get(&self, characteristic: CharacteristicId) -> Option<Vec<u8>> {
+        let inner = self.inner.lock().ok()?;
+        inner.values.get(&characteristic).cloned()
+    }
+
+    pub fn set(&self, characteristic: CharacteristicId, value: Vec<u8>) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.values.insert(characteristic, value);
+        }
+    }
+
+    pub fn remove(&self, characteristic: CharacteristicId) -> Option<Vec<u8>> {
+        let mut inner = self.inner.lock().ok()?;
+        inner.values.remove(&characteristic)
+    }
+
+    /// Answer a read from the store, `None` when it has to go to the application
+    pub(crate) fn read(
+        &self,
+        characteristic: CharacteristicId,
+        offset: u64,
+    ) -> Option<Result<Vec<u8>, RequestResponse>> {
+        let inner = self.inner.lock().ok()?;
+        if !inner.enabled {
+            return None;
+        }
+        let value = inner.values.get(&characteristic)?;
+        Some(
+            value
+                .get(offset as usize..)
+                .map(|value| value.to_vec())
+                .ok_or(RequestResponse::InvalidOffset),
+        )
+    }
+
+    // Apply a write the application accepted, replacing the value from the offset on.
+    // Offsets past the end of the stored value fail with InvalidOffset
+    pub(crate) fn write(
+        &self,
+        characteristic: CharacteristicId,
+        value: &[u8],
+        offset: u64,
+    ) -> Result<(), RequestResponse> {
+        let Ok(mut inner) = self.inner.lock() else {
+            return Ok(());
+        };
+        if !inner.enabled {
+            return Ok(());
+        }
+        let stored = inner.values.entry(characteristic).or_default();
+        if offset > stored.len() as u64 {
+            return Err(RequestResponse::InvalidOffset);
+        }
+        stored.truncate(offset as usize);
+        stored.extend_from_slice(value);
+        Ok(())
+    }
+
+    // Keep the value sent with update_characteristic
+    pub(crate) fn update(&self, characteristic: CharacteristicId, value: &[u8]) {
+        if let Ok(mut inner) = self.inner.lock() {
+            if inner.enabled {
+                inner.values.insert(characteristic, value.to_vec());
+            }
+        }
+    }
+}
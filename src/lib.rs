@@ -1,6 +1,10 @@
+pub mod advertisement;
 pub mod error;
 pub mod gatt;
 pub mod uuid;
 
 mod peripheral;
 pub use self::peripheral::{Peripheral, PeripheralImpl};
+
+#[cfg(feature = "mock")]
+pub use self::peripheral::mock;
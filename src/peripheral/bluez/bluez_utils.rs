@@ -1,15 +1,134 @@
-use crate::error::{self, Error, ErrorType};
-use bluer::gatt::local::CharacteristicControl;
+use crate::{
+    advertisement::{AdvertisementConfig, AdvertisementInclude},
+    error::{self, Error, ErrorType},
+    gatt::{
+        characteristic::CharacteristicId,
+        peripheral_event::{AddressType, LinkType},
+    },
+};
+use bluer::{
+    adv::{Advertisement, Feature, Type},
+    gatt::local::{
+        CharacteristicControl, CharacteristicNotifier, DescriptorControl, ServiceControl,
+    },
+    ErrorKind, InternalErrorKind,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
 use uuid::Uuid;
+
+// Notification session of an indicate-only characteristic, shared by all subscribed clients.
+// Locked until its indication is confirmed, so indications go out one at a time.
+pub(crate) struct IndicationSession(pub tokio::sync::Mutex<CharacteristicNotifier>);
+
+impl fmt::Debug for IndicationSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IndicationSession").finish_non_exhaustive()
+    }
+}
+
+pub(crate) type IndicationSessions = Arc<Mutex<HashMap<CharacteristicId, Arc<IndicationSession>>>>;
+
 #[derive(Debug)]
 pub(crate) struct CharNotifyHandler {
     pub service_uuid: Uuid,
     pub characteristic_uuid: Uuid,
-    pub control: CharacteristicControl,
+    // Shared with the handle table, never locked across an await
+    pub control: Arc<Mutex<CharacteristicControl>>,
+}
+
+// Control of a registered attribute, to read back the handle bluetoothd assigned to it
+#[derive(Debug)]
+pub(crate) enum HandleControl {
+    Service(ServiceControl),
+    Characteristic(Arc<Mutex<CharacteristicControl>>),
+    Descriptor(DescriptorControl),
+    // Generated by bluetoothd, which does not report its handle
+    Generated,
+}
+
+impl HandleControl {
+    pub fn handle(&self) -> Option<u16> {
+        let handle = match self {
+            HandleControl::Service(control) => control.handle(),
+            HandleControl::Characteristic(control) => control.lock().ok()?.handle(),
+            HandleControl::Descriptor(control) => control.handle(),
+            HandleControl::Generated => return None,
+        };
+        handle.ok().map(|handle| handle.get())
+    }
+}
+
+pub(crate) fn to_link_type(link: Option<bluer::gatt::local::LinkType>) -> Option<LinkType> {
+    match link? {
+        bluer::gatt::local::LinkType::Le => Some(LinkType::Le),
+        bluer::gatt::local::LinkType::BrEdr => Some(LinkType::BrEdr),
+    }
+}
+
+pub(crate) fn to_address_type(address_type: bluer::AddressType) -> AddressType {
+    match address_type {
+        bluer::AddressType::LePublic => AddressType::LePublic,
+        bluer::AddressType::LeRandom => AddressType::LeRandom,
+        bluer::AddressType::BrEdr => AddressType::BrEdr,
+    }
 }
 
 impl From<bluer::Error> for error::Error {
     fn from(error: bluer::Error) -> Self {
-        Error::from_string(error.to_string(), ErrorType::Bluez)
+        let error_type = match &error.kind {
+            // No default adapter, or its objects were removed along with it
+            ErrorKind::NotFound => ErrorType::AdapterNotFound,
+            ErrorKind::NotReady => ErrorType::NotPowered,
+            ErrorKind::NotAuthorized => ErrorType::PermissionDenied,
+            ErrorKind::InProgress => ErrorType::AlreadyInProgress,
+            ErrorKind::Internal(InternalErrorKind::DBus(name)) => match name.as_str() {
+                "org.freedesktop.DBus.Error.AccessDenied" => ErrorType::PermissionDenied,
+                // bluetoothd is not running
+                "org.freedesktop.DBus.Error.ServiceUnknown" => ErrorType::AdapterNotFound,
+                _ => ErrorType::Bluez,
+            },
+            // bluetoothd fails advertisements past the adapter's instances without a kind of its own
+            ErrorKind::Failed if error.message.contains("Maximum advertisements reached") => {
+                ErrorType::AdvertisingLimitReached
+            }
+            _ => ErrorType::Bluez,
+        };
+        Error::from_source(error, error_type)
+    }
+}
+
+pub(crate) fn to_bluer_advertisement(config: &AdvertisementConfig) -> Advertisement {
+    // Broadcast advertisements must not set the discoverable flag
+    let (advertisement_type, discoverable) = match config.connectable {
+        true => (Type::Peripheral, Some(config.discoverable)),
+        false => (Type::Broadcast, None),
+    };
+    Advertisement {
+        advertisement_type,
+        service_uuids: config.service_uuids.iter().cloned().collect(),
+        manufacturer_data: config.manufacturer_data.clone(),
+        service_data: config.service_data.clone(),
+        solicit_uuids: config.solicit_uuids.iter().cloned().collect(),
+        discoverable,
+        discoverable_timeout: discoverable.and(config.discoverable_timeout),
+        system_includes: config
+            .includes
+            .iter()
+            .map(|include| match include {
+                AdvertisementInclude::TxPower => Feature::TxPower,
+                AdvertisementInclude::Appearance => Feature::Appearance,
+                AdvertisementInclude::LocalName => Feature::LocalName,
+            })
+            .collect(),
+        local_name: config.local_name.clone(),
+        appearance: config.appearance,
+        min_interval: config.min_interval,
+        max_interval: config.max_interval,
+        tx_power: config.tx_power,
+        ..Default::default()
     }
 }
@@ -1,51 +1,92 @@
-use super::bluez_utils::CharNotifyHandler;
+use super::bluez_utils::{
+    to_link_type, CharNotifyHandler, HandleControl, IndicationSession, IndicationSessions,
+};
+use crate::gatt::characteristic::CharacteristicId;
+use crate::gatt::client_mtu::ClientMtus;
+use crate::gatt::descriptor::EXTENDED_PROPERTIES_UUID;
+use crate::gatt::long_read::LongReads;
 use crate::gatt::peripheral_event::{
-    PeripheralEvent, PeripheralRequest, ReadRequestResponse, RequestResponse, WriteRequestResponse,
+    DescriptorRequest, PeripheralEvent, PeripheralRequest, ReadRequestResponse, RequestResponse,
+    WriteRequestResponse,
 };
+use crate::gatt::prepared_write::PreparedWrites;
 use crate::gatt::properties::AttributePermission;
+use crate::gatt::response_timeout::ResponseTimeout;
+use crate::gatt::value_store::ValueStore;
 use crate::gatt::{characteristic, descriptor, properties, service};
 use bluer::gatt::local::{
-    characteristic_control, service_control, Characteristic, CharacteristicControl,
-    CharacteristicControlHandle, CharacteristicNotify, CharacteristicNotifyMethod,
-    CharacteristicWrite, CharacteristicWriteMethod, CharacteristicWriteRequest, Descriptor,
+    characteristic_control, descriptor_control, service_control, Characteristic,
+    CharacteristicControl, CharacteristicNotify, CharacteristicNotifyMethod, CharacteristicWrite,
+    CharacteristicWriteMethod, CharacteristicWriteRequest, Descriptor, DescriptorControl,
     DescriptorRead, DescriptorReadRequest, DescriptorWrite, DescriptorWriteRequest, ReqError,
     Service,
 };
 use bluer::gatt::local::{CharacteristicRead, CharacteristicReadRequest};
+use bluer::gatt::WriteOp;
 use futures::FutureExt;
+use std::num::NonZeroU16;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::oneshot;
 use uuid::Uuid;
 
+// Quiet period after which the prepared writes handed over by bluetoothd are complete
+const PREPARED_WRITE_IDLE: Duration = Duration::from_millis(100);
+
+// Handles shared by the request handlers of all served attributes
+#[derive(Debug, Clone)]
+pub(crate) struct GattContext {
+    pub sender_tx: Sender<PeripheralEvent>,
+    pub value_store: ValueStore,
+    pub long_reads: LongReads,
+    pub prepared_writes: PreparedWrites,
+    pub response_timeout: ResponseTimeout,
+    pub client_mtus: ClientMtus,
+    pub indications: IndicationSessions,
+}
+
+// Handle controls are returned in the order of `service_attributes`
 pub fn parse_services(
     gatt_services: Vec<service::Service>,
-    sender_tx: Sender<PeripheralEvent>,
-) -> (Vec<CharNotifyHandler>, Vec<Service>) {
+    context: &GattContext,
+) -> (Vec<CharNotifyHandler>, Vec<Service>, Vec<HandleControl>) {
     let mut services: Vec<Service> = vec![];
     let mut char_notify_handlers: Vec<CharNotifyHandler> = vec![];
+    let mut handle_controls: Vec<HandleControl> = vec![];
 
     for service in gatt_services.iter().clone() {
-        let (_, service_handle) = service_control();
+        let (service_control, service_handle) = service_control();
+        handle_controls.push(HandleControl::Service(service_control));
 
         let mut characteristics: Vec<Characteristic> = Vec::new();
-        let service_uuid = service.uuid.clone();
+        let service_uuid = service.uuid;
 
         for char in service.characteristics.clone() {
-            let result = parse_characteristic(char.clone(), service.uuid, sender_tx.clone());
-
-            if let Some(char_control) = result.1 {
+            let (characteristic, char_control, descriptor_controls) =
+                parse_characteristic(char.clone(), service.uuid, context);
+
+            // Indicate-only characteristics get their subscriptions through a notification session
+            if characteristic
+                .notify
+                .as_ref()
+                .is_some_and(|notify| matches!(notify.method, CharacteristicNotifyMethod::Io))
+            {
                 char_notify_handlers.push(CharNotifyHandler {
-                    service_uuid: service_uuid.clone(),
-                    characteristic_uuid: char.uuid.clone(),
-                    control: char_control,
+                    service_uuid,
+                    characteristic_uuid: char.uuid,
+                    control: char_control.clone(),
                 });
             }
+            handle_controls.push(HandleControl::Characteristic(char_control));
+            handle_controls.extend(descriptor_controls);
 
-            characteristics.push(result.0);
+            characteristics.push(characteristic);
         }
 
         let service = Service {
             uuid: service.uuid,
+            handle: service.handle.and_then(NonZeroU16::new),
             primary: service.primary,
             characteristics,
             control_handle: service_handle,
@@ -54,37 +95,71 @@ pub fn parse_services(
 
         services.push(service);
     }
-    (char_notify_handlers, services)
+    (char_notify_handlers, services, handle_controls)
 }
 
 fn parse_characteristic(
     characteristic: characteristic::Characteristic,
     service_uuid: Uuid,
-    sender_tx: Sender<PeripheralEvent>,
-) -> (Characteristic, Option<CharacteristicControl>) {
-    let descriptors: Vec<Descriptor> = characteristic
-        .descriptors
-        .iter()
-        .map(|data| parse_descriptor(data.clone()))
-        .collect();
-
-    let char_notify = get_characteristic_notify(characteristic.clone());
-
-    let mut control: Option<CharacteristicControl> = None;
-
-    let control_handle = match char_notify {
-        Some(_) => {
-            let (ctrl, handle) = characteristic_control();
-            control = Some(ctrl);
-            handle
+    context: &GattContext,
+) -> (
+    Characteristic,
+    Arc<Mutex<CharacteristicControl>>,
+    Vec<HandleControl>,
+) {
+    let mut descriptors: Vec<Descriptor> = Vec::new();
+    let mut descriptor_controls: Vec<HandleControl> = Vec::new();
+    for data in characteristic.descriptors.iter() {
+        // bluetoothd generates the extended properties from the write flags
+        if data.uuid == EXTENDED_PROPERTIES_UUID {
+            descriptor_controls.push(HandleControl::Generated);
+            continue;
         }
-        None => CharacteristicControlHandle::default(),
-    };
+        let (descriptor, control) = parse_descriptor(
+            data.clone(),
+            service_uuid,
+            characteristic.uuid,
+            context.sender_tx.clone(),
+            context.response_timeout.clone(),
+            context.client_mtus.clone(),
+        );
+        descriptors.push(descriptor);
+        descriptor_controls.push(HandleControl::Descriptor(control));
+    }
+    if characteristic
+        .extended_properties()
+        .is_some_and(|properties| properties.writable_auxiliaries)
+    {
+        log::warn!("bluer cannot set writable auxiliaries, only reliable writes are supported");
+    }
+
+    let char_notify = get_characteristic_notify(characteristic.clone(), service_uuid, context);
+
+    // Every characteristic gets a control to read back its handle, only the ones with
+    // notify receive events through it
+    let (control, control_handle) = characteristic_control();
 
     let char = Characteristic {
         uuid: characteristic.uuid,
-        read: get_characteristic_read(characteristic.clone(), service_uuid, sender_tx.clone()),
-        write: get_characteristic_write(characteristic.clone(), service_uuid, sender_tx.clone()),
+        handle: characteristic.handle.and_then(NonZeroU16::new),
+        read: get_characteristic_read(
+            characteristic.clone(),
+            service_uuid,
+            context.sender_tx.clone(),
+            context.value_store.clone(),
+            context.long_reads.clone(),
+            context.response_timeout.clone(),
+            context.client_mtus.clone(),
+        ),
+        write: get_characteristic_write(
+            characteristic.clone(),
+            service_uuid,
+            context.sender_tx.clone(),
+            context.value_store.clone(),
+            context.prepared_writes.clone(),
+            context.response_timeout.clone(),
+            context.client_mtus.clone(),
+        ),
         notify: char_notify,
         broadcast: characteristic
             .properties
@@ -93,13 +168,17 @@ fn parse_characteristic(
         descriptors, // TODO: fix descriptors
         ..Default::default()
     };
-    return (char, control);
+    (char, Arc::new(Mutex::new(control)), descriptor_controls)
 }
 
 fn get_characteristic_read(
     characteristic: characteristic::Characteristic,
     service_uuid: Uuid,
     sender_tx: Sender<PeripheralEvent>,
+    value_store: ValueStore,
+    long_reads: LongReads,
+    response_timeout: ResponseTimeout,
+    client_mtus: ClientMtus,
 ) -> Option<CharacteristicRead> {
     if !characteristic
         .properties
@@ -112,30 +191,56 @@ fn get_characteristic_read(
         .permissions
         .contains(&AttributePermission::ReadEncryptionRequired);
 
-    return Some(CharacteristicRead {
+    // Characteristics with a static or stored value are answered here, others by the application
+    Some(CharacteristicRead {
         read: true,
         secure_read: is_secure,
         fun: Box::new(move |request: CharacteristicReadRequest| {
+            client_mtus.update(&request.device_address.to_string(), Some(request.mtu));
             let sender_tx_clone = sender_tx.clone();
+            let value = characteristic.value.clone();
+            let characteristic_uuid = characteristic.uuid;
+            let stored = value_store.read(
+                CharacteristicId::new(service_uuid, characteristic_uuid),
+                request.offset as u64,
+            );
+            let long_reads = long_reads.clone();
+            let response_timeout = response_timeout.clone();
             async move {
-                return on_read_request(
-                    sender_tx_clone,
-                    request,
-                    service_uuid,
-                    characteristic.uuid,
-                )
-                .await;
+                if let Some(value) = value {
+                    return read_static_value(&value, request.offset);
+                }
+                match stored {
+                    Some(result) => {
+                        result.map_err(|response| response.to_req_err().unwrap_or(ReqError::Failed))
+                    }
+                    None => {
+                        on_read_request(
+                            sender_tx_clone,
+                            request,
+                            service_uuid,
+                            characteristic_uuid,
+                            long_reads,
+                            response_timeout,
+                        )
+                        .await
+                    }
+                }
             }
             .boxed()
         }),
         ..Default::default()
-    });
+    })
 }
 
 fn get_characteristic_write(
     characteristic: characteristic::Characteristic,
     service_uuid: Uuid,
     sender_tx: Sender<PeripheralEvent>,
+    value_store: ValueStore,
+    prepared_writes: PreparedWrites,
+    response_timeout: ResponseTimeout,
+    client_mtus: ClientMtus,
 ) -> Option<CharacteristicWrite> {
     let is_write = characteristic
         .properties
@@ -155,33 +260,72 @@ fn get_characteristic_write(
         .permissions
         .contains(&AttributePermission::WriteEncryptionRequired);
 
-    return Some(CharacteristicWrite {
+    Some(CharacteristicWrite {
         write: is_write,
         write_without_response: is_write_with_response,
+        reliable_write: characteristic
+            .extended_properties()
+            .is_some_and(|properties| properties.reliable_write),
         authenticated_signed_writes: is_authnticated_signed_write,
         secure_write: is_write_encryption,
         method: CharacteristicWriteMethod::Fun(Box::new(
             move |value: Vec<u8>, request: CharacteristicWriteRequest| {
+                client_mtus.update(&request.device_address.to_string(), Some(request.mtu));
                 let sender_tx_clone = sender_tx.clone();
+                let value_store = value_store.clone();
+                let prepared_writes = prepared_writes.clone();
+                let response_timeout = response_timeout.clone();
                 async move {
-                    return on_write_request(
+                    let offset = request.offset as u64;
+                    let peripheral_request = PeripheralRequest {
+                        client: request.device_address.to_string(),
+                        service: service_uuid,
+                        characteristic: characteristic.uuid,
+                        mtu: Some(request.mtu),
+                        link: to_link_type(request.link),
+                    };
+                    if request.op_type == WriteOp::Reliable
+                        && prepared_writes.is_enabled()
+                        && prepared_writes.is_best_effort()
+                    {
+                        return on_prepared_write(
+                            sender_tx_clone,
+                            peripheral_request,
+                            offset,
+                            value,
+                            prepared_writes,
+                            value_store,
+                            response_timeout,
+                        )
+                        .await;
+                    }
+                    send_write_request(
                         sender_tx_clone,
-                        request,
-                        service_uuid,
-                        characteristic.uuid,
-                        value,
+                        peripheral_request,
+                        value.clone(),
+                        offset,
+                        response_timeout,
                     )
-                    .await;
+                    .await?;
+                    value_store
+                        .write(
+                            CharacteristicId::new(service_uuid, characteristic.uuid),
+                            &value,
+                            offset,
+                        )
+                        .map_err(|response| response.to_req_err().unwrap_or(ReqError::Failed))
                 }
                 .boxed()
             },
         )),
         ..Default::default()
-    });
+    })
 }
 
 fn get_characteristic_notify(
     characteristic: characteristic::Characteristic,
+    service_uuid: Uuid,
+    context: &GattContext,
 ) -> Option<CharacteristicNotify> {
     let notify = characteristic
         .properties
@@ -200,24 +344,135 @@ fn get_characteristic_notify(
         return None;
     }
 
-    return Some(CharacteristicNotify {
-        notify: notify || notify_encryption_required,
-        indicate: indicate || indicate_encryption_required,
-        method: CharacteristicNotifyMethod::Io,
+    let notify = notify || notify_encryption_required;
+    let indicate = indicate || indicate_encryption_required;
+    // bluetoothd passes indication confirmations on to notification sessions only, and only
+    // for characteristics that cannot notify. Acquired sockets do not report them.
+    let method = if indicate && !notify {
+        indication_session(
+            CharacteristicId::new(service_uuid, characteristic.uuid),
+            context.sender_tx.clone(),
+            context.indications.clone(),
+        )
+    } else {
+        CharacteristicNotifyMethod::Io
+    };
+
+    Some(CharacteristicNotify {
+        notify,
+        indicate,
+        method,
         ..Default::default()
-    });
+    })
+}
+
+// bluetoothd starts one session for all subscribed clients and does not tell which ones
+// they are, so its subscription events carry no client
+fn indication_session(
+    characteristic: CharacteristicId,
+    sender_tx: Sender<PeripheralEvent>,
+    indications: IndicationSessions,
+) -> CharacteristicNotifyMethod {
+    CharacteristicNotifyMethod::Fun(Box::new(move |notifier| {
+        let sender_tx = sender_tx.clone();
+        let indications = indications.clone();
+        async move {
+            let stopped = notifier.stopped();
+            let notifier = Arc::new(IndicationSession(tokio::sync::Mutex::new(notifier)));
+            if let Ok(mut sessions) = indications.lock() {
+                sessions.insert(characteristic, notifier.clone());
+            } else {
+                log::error!("Failed to lock indication sessions for adding a session");
+            }
+
+            let request = PeripheralRequest {
+                client: String::new(),
+                service: characteristic.service,
+                characteristic: characteristic.characteristic,
+                mtu: None,
+                link: None,
+            };
+            // StartNotify waits for this future, watch the session on its own task
+            tokio::spawn(async move {
+                send_subscription_event(&sender_tx, request.clone(), true).await;
+                stopped.await;
+                if let Ok(mut sessions) = indications.lock() {
+                    // bluetoothd may have started a new session in the meantime
+                    if sessions
+                        .get(&characteristic)
+                        .is_some_and(|current| Arc::ptr_eq(current, &notifier))
+                    {
+                        sessions.remove(&characteristic);
+                    }
+                } else {
+                    log::error!("Failed to lock indication sessions for removing a session");
+                }
+                send_subscription_event(&sender_tx, request, false).await;
+            });
+        }
+        .boxed()
+    }))
+}
+
+async fn send_subscription_event(
+    sender_tx: &Sender<PeripheralEvent>,
+    request: PeripheralRequest,
+    subscribed: bool,
+) {
+    if let Err(err) = sender_tx
+        .send(PeripheralEvent::CharacteristicSubscriptionUpdate {
+            request,
+            subscribed,
+        })
+        .await
+    {
+        log::error!("Error sending subscription event: {:?}", err);
+    }
 }
 
-fn parse_descriptor(descriptor: descriptor::Descriptor) -> Descriptor {
-    return Descriptor {
+fn parse_descriptor(
+    descriptor: descriptor::Descriptor,
+    service_uuid: Uuid,
+    characteristic_uuid: Uuid,
+    sender_tx: Sender<PeripheralEvent>,
+    response_timeout: ResponseTimeout,
+    client_mtus: ClientMtus,
+) -> (Descriptor, DescriptorControl) {
+    let (control, control_handle) = descriptor_control();
+    let descriptor = Descriptor {
         uuid: descriptor.uuid,
-        read: get_descriptor_read(descriptor.clone()),
-        write: get_descriptor_write(descriptor.clone()),
+        handle: descriptor.handle.and_then(NonZeroU16::new),
+        read: get_descriptor_read(
+            descriptor.clone(),
+            service_uuid,
+            characteristic_uuid,
+            sender_tx.clone(),
+            response_timeout.clone(),
+            client_mtus.clone(),
+        ),
+        write: get_descriptor_write(
+            descriptor.clone(),
+            service_uuid,
+            characteristic_uuid,
+            sender_tx,
+            response_timeout,
+            client_mtus,
+        ),
+        control_handle,
         ..Default::default()
     };
+    (descriptor, control)
 }
 
-fn get_descriptor_read(descriptor: descriptor::Descriptor) -> Option<DescriptorRead> {
+// Descriptors with a static value are answered here, others by the application
+fn get_descriptor_read(
+    descriptor: descriptor::Descriptor,
+    service_uuid: Uuid,
+    characteristic_uuid: Uuid,
+    sender_tx: Sender<PeripheralEvent>,
+    response_timeout: ResponseTimeout,
+    client_mtus: ClientMtus,
+) -> Option<DescriptorRead> {
     if !descriptor
         .properties
         .contains(&properties::CharacteristicProperty::Read)
@@ -228,25 +483,45 @@ fn get_descriptor_read(descriptor: descriptor::Descriptor) -> Option<DescriptorR
     let is_secure = descriptor
         .permissions
         .contains(&AttributePermission::ReadEncryptionRequired);
-    let value = descriptor.value;
-    return Some(DescriptorRead {
+    Some(DescriptorRead {
         read: true,
         secure_read: is_secure,
-        fun: Box::new(move |_: DescriptorReadRequest| {
-            let value_clone = value.clone();
+        fun: Box::new(move |request: DescriptorReadRequest| {
+            let sender_tx_clone = sender_tx.clone();
+            let value = descriptor.value.clone();
+            let descriptor_uuid = descriptor.uuid;
+            let response_timeout = response_timeout.clone();
+            let client_mtus = client_mtus.clone();
             async move {
-                if value_clone.is_none() {
-                    return Err(ReqError::Failed);
+                match value {
+                    Some(value) => read_static_value(&value, request.offset),
+                    None => {
+                        on_descriptor_read_request(
+                            sender_tx_clone,
+                            request,
+                            CharacteristicId::new(service_uuid, characteristic_uuid),
+                            descriptor_uuid,
+                            response_timeout,
+                            client_mtus,
+                        )
+                        .await
+                    }
                 }
-                return Ok(value_clone.unwrap());
             }
             .boxed()
         }),
         ..Default::default()
-    });
+    })
 }
 
-fn get_descriptor_write(descriptor: descriptor::Descriptor) -> Option<DescriptorWrite> {
+fn get_descriptor_write(
+    descriptor: descriptor::Descriptor,
+    service_uuid: Uuid,
+    characteristic_uuid: Uuid,
+    sender_tx: Sender<PeripheralEvent>,
+    response_timeout: ResponseTimeout,
+    client_mtus: ClientMtus,
+) -> Option<DescriptorWrite> {
     let is_write = descriptor
         .properties
         .contains(&properties::CharacteristicProperty::Write);
@@ -265,19 +540,38 @@ fn get_descriptor_write(descriptor: descriptor::Descriptor) -> Option<Descriptor
         .permissions
         .contains(&AttributePermission::WriteEncryptionRequired);
 
-    return Some(DescriptorWrite {
+    Some(DescriptorWrite {
         write: is_write || is_write_with_response,
         encrypt_authenticated_write: is_authnticated_signed_write,
         secure_write: is_write_encryption,
-        fun: Box::new(move |value: Vec<u8>, _: DescriptorWriteRequest| {
+        fun: Box::new(move |value: Vec<u8>, request: DescriptorWriteRequest| {
+            let sender_tx_clone = sender_tx.clone();
+            let descriptor_uuid = descriptor.uuid;
+            let response_timeout = response_timeout.clone();
+            let client_mtus = client_mtus.clone();
             async move {
-                log::info!("Descriptor Write {value:?}");
-                return Ok(());
+                on_descriptor_write_request(
+                    sender_tx_clone,
+                    request,
+                    CharacteristicId::new(service_uuid, characteristic_uuid),
+                    descriptor_uuid,
+                    value,
+                    response_timeout,
+                    client_mtus,
+                )
+                .await
             }
             .boxed()
         }),
         ..Default::default()
-    });
+    })
+}
+
+fn read_static_value(value: &[u8], offset: u16) -> Result<Vec<u8>, ReqError> {
+    value
+        .get(offset as usize..)
+        .map(|value| value.to_vec())
+        .ok_or(ReqError::InvalidOffset)
 }
 
 /// Handle Requests
@@ -286,46 +580,215 @@ async fn on_read_request(
     request: CharacteristicReadRequest,
     service_uuid: Uuid,
     characteristic: Uuid,
+    long_reads: LongReads,
+    response_timeout: ResponseTimeout,
 ) -> Result<Vec<u8>, ReqError> {
+    let client = request.device_address.to_string();
+    let offset = request.offset as u64;
+    let characteristic_id = CharacteristicId::new(service_uuid, characteristic);
+    if let Some(result) = long_reads.read(&client, characteristic_id, offset, Some(request.mtu)) {
+        return result.map_err(|response| response.to_req_err().unwrap_or(ReqError::Failed));
+    }
+
+    let peripheral_request = PeripheralRequest {
+        client: client.clone(),
+        service: service_uuid,
+        characteristic,
+        mtu: Some(request.mtu),
+        link: to_link_type(request.link),
+    };
     let (res_tx, res_rx) = oneshot::channel::<ReadRequestResponse>();
     if let Err(err) = sender_tx
         .send(PeripheralEvent::ReadRequest {
-            request: PeripheralRequest {
-                client: request.device_address.to_string(),
-                service: service_uuid,
-                characteristic,
+            request: peripheral_request.clone(),
+            offset: long_reads.request_offset(offset),
+            responder: res_tx,
+        })
+        .await
+    {
+        log::error!("Error sending read request event: {:?}", err);
+    }
+
+    let res = response_timeout
+        .wait(&sender_tx, peripheral_request, None, res_rx)
+        .await
+        .map_err(|response| response.to_req_err().unwrap_or(ReqError::Failed))?;
+    if let Some(err) = res.response.to_req_err() {
+        return Err(err);
+    }
+    long_reads
+        .respond(
+            &client,
+            characteristic_id,
+            offset,
+            Some(request.mtu),
+            res.value,
+        )
+        .map_err(|response| response.to_req_err().unwrap_or(ReqError::Failed))
+}
+
+// bluetoothd executes the queue of prepared writes itself, handing over one fragment after
+// the other, so the reassembled values are delivered once no fragment followed for a moment.
+// This is a guess: the client already got its responses by then, rejected values can only
+// be logged, and queues executed within the pause are merged. Hence the explicit opt-in.
+async fn on_prepared_write(
+    sender_tx: Sender<PeripheralEvent>,
+    request: PeripheralRequest,
+    offset: u64,
+    value: Vec<u8>,
+    prepared_writes: PreparedWrites,
+    value_store: ValueStore,
+    response_timeout: ResponseTimeout,
+) -> Result<(), ReqError> {
+    let (mtu, link) = (request.mtu, request.link);
+    let client = request.client;
+    prepared_writes
+        .prepare(
+            &client,
+            request.service,
+            request.characteristic,
+            offset,
+            &value,
+        )
+        .map_err(|response| response.to_req_err().unwrap_or(ReqError::Failed))?;
+
+    tokio::spawn(async move {
+        tokio::time::sleep(PREPARED_WRITE_IDLE).await;
+        for write in prepared_writes.take(&client, PREPARED_WRITE_IDLE) {
+            let request = PeripheralRequest {
+                client: client.clone(),
+                service: write.service,
+                characteristic: write.characteristic,
+                mtu,
+                link,
+            };
+            let characteristic = request.characteristic_id();
+            match send_write_request(
+                sender_tx.clone(),
+                request,
+                write.value.clone(),
+                0,
+                response_timeout.clone(),
+            )
+            .await
+            {
+                Ok(()) => {
+                    if let Err(err) = value_store.write(characteristic, &write.value, 0) {
+                        log::error!(
+                            "Prepared write to {} not stored: {err:?}",
+                            write.characteristic
+                        );
+                    }
+                }
+                Err(err) => log::error!(
+                    "Prepared write to {} rejected: {err:?}",
+                    write.characteristic
+                ),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn send_write_request(
+    sender_tx: Sender<PeripheralEvent>,
+    request: PeripheralRequest,
+    value: Vec<u8>,
+    offset: u64,
+    response_timeout: ResponseTimeout,
+) -> Result<(), ReqError> {
+    let (res_tx, res_rx) = oneshot::channel::<WriteRequestResponse>();
+    if let Err(err) = sender_tx
+        .send(PeripheralEvent::WriteRequest {
+            request: request.clone(),
+            offset,
+            value,
+            responder: res_tx,
+        })
+        .await
+    {
+        log::error!("Error sending write request event: {:?}", err);
+    }
+
+    let res = response_timeout
+        .wait(&sender_tx, request, None, res_rx)
+        .await
+        .map_err(|response| response.to_req_err().unwrap_or(ReqError::Failed))?;
+    match res.response.to_req_err() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+async fn on_descriptor_read_request(
+    sender_tx: Sender<PeripheralEvent>,
+    request: DescriptorReadRequest,
+    characteristic: CharacteristicId,
+    descriptor: Uuid,
+    response_timeout: ResponseTimeout,
+    client_mtus: ClientMtus,
+) -> Result<Vec<u8>, ReqError> {
+    let client = request.device_address.to_string();
+    // bluetoothd does not report the MTU with descriptor requests
+    let mtu = client_mtus.get(&client);
+    let (res_tx, res_rx) = oneshot::channel::<ReadRequestResponse>();
+    if let Err(err) = sender_tx
+        .send(PeripheralEvent::DescriptorReadRequest {
+            request: DescriptorRequest {
+                client: client.clone(),
+                service: characteristic.service,
+                characteristic: characteristic.characteristic,
+                descriptor,
+                mtu,
+                link: to_link_type(request.link),
             },
             offset: request.offset as u64,
             responder: res_tx,
         })
         .await
     {
-        eprintln!("Error sending read request event: {:?}", err);
+        log::error!("Error sending descriptor read request event: {:?}", err);
     }
 
-    if let Ok(res) = res_rx.await {
-        if let Some(err) = res.response.to_req_err() {
-            return Err(err);
-        }
-        return Ok(res.value);
+    let peripheral_request = PeripheralRequest {
+        client,
+        service: characteristic.service,
+        characteristic: characteristic.characteristic,
+        mtu,
+        link: to_link_type(request.link),
+    };
+    let res = response_timeout
+        .wait(&sender_tx, peripheral_request, Some(descriptor), res_rx)
+        .await
+        .map_err(|response| response.to_req_err().unwrap_or(ReqError::Failed))?;
+    match res.response.to_req_err() {
+        Some(err) => Err(err),
+        None => Ok(res.value),
     }
-    return Err(ReqError::Failed);
 }
 
-async fn on_write_request(
+async fn on_descriptor_write_request(
     sender_tx: Sender<PeripheralEvent>,
-    request: CharacteristicWriteRequest,
-    service_uuid: Uuid,
-    characteristic: Uuid,
+    request: DescriptorWriteRequest,
+    characteristic: CharacteristicId,
+    descriptor: Uuid,
     value: Vec<u8>,
+    response_timeout: ResponseTimeout,
+    client_mtus: ClientMtus,
 ) -> Result<(), ReqError> {
+    let client = request.device_address.to_string();
+    // bluetoothd does not report the MTU with descriptor requests
+    let mtu = client_mtus.get(&client);
     let (res_tx, res_rx) = oneshot::channel::<WriteRequestResponse>();
     if let Err(err) = sender_tx
-        .send(PeripheralEvent::WriteRequest {
-            request: PeripheralRequest {
-                client: request.device_address.to_string(),
-                service: service_uuid,
-                characteristic,
+        .send(PeripheralEvent::DescriptorWriteRequest {
+            request: DescriptorRequest {
+                client: client.clone(),
+                service: characteristic.service,
+                characteristic: characteristic.characteristic,
+                descriptor,
+                mtu,
+                link: to_link_type(request.link),
             },
             offset: request.offset as u64,
             value,
@@ -333,26 +796,44 @@ async fn on_write_request(
         })
         .await
     {
-        eprintln!("Error sending read request event: {:?}", err);
+        log::error!("Error sending descriptor write request event: {:?}", err);
     }
 
-    if let Ok(res) = res_rx.await {
-        if let Some(err) = res.response.to_req_err() {
-            return Err(err);
-        }
-        return Ok(());
+    let peripheral_request = PeripheralRequest {
+        client,
+        service: characteristic.service,
+        characteristic: characteristic.characteristic,
+        mtu,
+        link: to_link_type(request.link),
+    };
+    let res = response_timeout
+        .wait(&sender_tx, peripheral_request, Some(descriptor), res_rx)
+        .await
+        .map_err(|response| response.to_req_err().unwrap_or(ReqError::Failed))?;
+    match res.response.to_req_err() {
+        Some(err) => Err(err),
+        None => Ok(()),
     }
-    return Err(ReqError::Failed);
 }
 
+// bluetoothd answers NotPermitted with the read or write not permitted error of the request,
+// and Failed with the application error 0x80
 impl RequestResponse {
     fn to_req_err(self) -> Option<ReqError> {
         match self {
             RequestResponse::Success => None,
-            RequestResponse::InvalidHandle => Some(ReqError::Failed),
+            RequestResponse::ReadNotPermitted | RequestResponse::WriteNotPermitted => {
+                Some(ReqError::NotPermitted)
+            }
+            RequestResponse::InsufficientAuthentication
+            | RequestResponse::InsufficientAuthorization
+            | RequestResponse::InsufficientEncryption
+            | RequestResponse::InsufficientEncryptionKeySize => Some(ReqError::NotAuthorized),
             RequestResponse::RequestNotSupported => Some(ReqError::NotSupported),
             RequestResponse::InvalidOffset => Some(ReqError::InvalidOffset),
-            RequestResponse::UnlikelyError => Some(ReqError::Failed),
+            RequestResponse::InvalidAttributeValueLength => Some(ReqError::InvalidValueLength),
+            RequestResponse::ProcedureAlreadyInProgress => Some(ReqError::InProgress),
+            _ => Some(ReqError::Failed),
         }
     }
 }
@@ -2,41 +2,70 @@ mod bluez_utils;
 mod characteristic_utils;
 
 use crate::{
+    advertisement::{AdvertisementConfig, AdvertisementField, AdvertisementId},
     error::{Error, ErrorType},
     gatt::{
-        peripheral_event::{PeripheralEvent, PeripheralRequest},
+        attribute::{service_attributes, Attribute},
+        characteristic::CharacteristicId,
+        client_mtu::ClientMtus,
+        long_read::LongReads,
+        peripheral_event::{
+            AddressType, NotificationResult, NotificationStatus, PeripheralEvent, PeripheralRequest,
+        },
+        prepared_write::PreparedWrites,
+        response_timeout::ResponseTimeout,
         service,
+        value_store::ValueStore,
     },
 };
 use async_trait::async_trait;
 use bluer::{
-    adv::{Advertisement, AdvertisementHandle},
+    adv::AdvertisementHandle,
+    agent::AgentHandle,
     gatt::{
         local::{Application, ApplicationHandle, CharacteristicControlEvent},
         CharacteristicWriter,
     },
-    Adapter, AdapterEvent, AdapterProperty,
+    Adapter, AdapterEvent, AdapterProperty, Address, Device, DeviceEvent, DeviceProperty,
 };
-use bluez_utils::CharNotifyHandler;
-use characteristic_utils::parse_services;
-use futures::{channel::oneshot, StreamExt};
+use bluez_utils::{
+    to_address_type, to_bluer_advertisement, CharNotifyHandler, HandleControl, IndicationSession,
+    IndicationSessions,
+};
+use characteristic_utils::{parse_services, GattContext};
+use futures::{channel::oneshot, future, stream::FuturesUnordered, StreamExt};
 use std::{
-    collections::{BTreeMap, BTreeSet, HashMap},
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
     sync::{Arc, Mutex},
+    task::Poll,
 };
 use tokio::sync::mpsc::Sender;
 use uuid::Uuid;
 
 use super::PeripheralImpl;
 
+// Notification writers per characteristic, one for each subscribed client
+type Writers = Arc<Mutex<HashMap<CharacteristicId, HashMap<Address, Arc<CharacteristicWriter>>>>>;
+
 #[derive(Debug)]
 pub struct Peripheral {
     pub adapter: Adapter,
     services: Vec<service::Service>,
-    adv_handle: Option<AdvertisementHandle>,
+    advertisements: HashMap<AdvertisementId, AdvertisementHandle>,
+    last_advertisement: AdvertisementId,
     app_handle: Option<ApplicationHandle>,
+    // Controls of the served attributes, in the order of `service_attributes`
+    handle_controls: Vec<HandleControl>,
+    _agent_handle: Option<AgentHandle>,
     sender_tx: Sender<PeripheralEvent>,
-    writers: Arc<Mutex<HashMap<Uuid, Arc<CharacteristicWriter>>>>,
+    writers: Writers,
+    indications: IndicationSessions,
+    value_store: ValueStore,
+    long_reads: LongReads,
+    prepared_writes: PreparedWrites,
+    response_timeout: ResponseTimeout,
+    client_mtus: ClientMtus,
     _drop_tx: oneshot::Sender<()>,
 }
 
@@ -49,8 +78,9 @@ impl PeripheralImpl for Peripheral {
         agent: Option<bluer::agent::Agent>,
     ) -> Result<Self, Error> {
         let session = bluer::Session::new().await?;
-        if let Some(agent) = agent {
-            session.register_agent(agent);
+        let agent_handle = match agent {
+            Some(agent) => Some(session.register_agent(agent).await?),
+            None => None,
         };
         let adapter = session.default_adapter().await?;
         adapter.set_powered(true).await?;
@@ -60,19 +90,51 @@ impl PeripheralImpl for Peripheral {
             adapter.address().await?
         );
 
+        let client_mtus = ClientMtus::default();
+        let long_reads = LongReads::default();
         let (drop_tx, drop_rx) = oneshot::channel();
         if let Ok(mut adapter_stream) = adapter.events().await {
             let sender = sender_tx.clone();
+            let adapter = adapter.clone();
+            let client_mtus = client_mtus.clone();
+            let long_reads = long_reads.clone();
             tokio::spawn(async move {
                 let stream_future = async {
-                    while let Some(AdapterEvent::PropertyChanged(event)) =
-                        adapter_stream.next().await
-                    {
+                    // Watchers run in this task, so they stop with the peripheral. A device added
+                    // while listing the known ones shows up twice, watch each address once.
+                    let mut device_watchers = FuturesUnordered::new();
+                    let mut watched: HashSet<Address> = HashSet::new();
+                    for address in adapter.device_addresses().await.unwrap_or_default() {
+                        if !watched.insert(address) {
+                            continue;
+                        }
+                        if let Ok(device) = adapter.device(address) {
+                            device_watchers.push(watch_device(
+                                device,
+                                sender.clone(),
+                                client_mtus.clone(),
+                                long_reads.clone(),
+                            ));
+                        }
+                    }
+
+                    loop {
+                        let event = tokio::select! {
+                            event = adapter_stream.next() => event,
+                            Some(address) = device_watchers.next() => {
+                                watched.remove(&address);
+                                continue;
+                            }
+                        };
                         match event {
-                            AdapterProperty::ActiveAdvertisingInstances(i) => {
+                            Some(AdapterEvent::PropertyChanged(
+                                AdapterProperty::ActiveAdvertisingInstances(i),
+                            )) => {
                                 log::debug!("ActiveAdvertisingInstances: {i}")
                             }
-                            AdapterProperty::Powered(powered) => {
+                            Some(AdapterEvent::PropertyChanged(AdapterProperty::Powered(
+                                powered,
+                            ))) => {
                                 if let Err(err) = sender
                                     .send(PeripheralEvent::StateUpdate {
                                         is_powered: powered,
@@ -82,7 +144,22 @@ impl PeripheralImpl for Peripheral {
                                     log::error!("Error sending state update event: {:?}", err);
                                 }
                             }
-                            _ => {}
+                            // bluetoothd adds unknown centrals when they connect
+                            Some(AdapterEvent::DeviceAdded(address)) => {
+                                if !watched.insert(address) {
+                                    continue;
+                                }
+                                if let Ok(device) = adapter.device(address) {
+                                    device_watchers.push(watch_device(
+                                        device,
+                                        sender.clone(),
+                                        client_mtus.clone(),
+                                        long_reads.clone(),
+                                    ));
+                                }
+                            }
+                            Some(_) => {}
+                            None => break,
                         }
                     }
                 };
@@ -96,10 +173,19 @@ impl PeripheralImpl for Peripheral {
         Ok(Peripheral {
             adapter,
             services: Vec::new(),
-            adv_handle: None,
+            advertisements: HashMap::new(),
+            last_advertisement: AdvertisementId::PRIMARY,
             app_handle: None,
+            handle_controls: Vec::new(),
+            _agent_handle: agent_handle,
             sender_tx,
             writers: Arc::new(Mutex::new(HashMap::new())),
+            indications: Arc::new(Mutex::new(HashMap::new())),
+            value_store: ValueStore::default(),
+            long_reads,
+            prepared_writes: PreparedWrites::default(),
+            response_timeout: ResponseTimeout::default(),
+            client_mtus,
             _drop_tx: drop_tx,
         })
     }
@@ -111,133 +197,570 @@ impl PeripheralImpl for Peripheral {
 
     async fn is_advertising(&mut self) -> Result<bool, Error> {
         let result = self.adapter.active_advertising_instances().await?;
-        return Ok(result > 0 && self.adv_handle.is_some());
+        return Ok(result > 0 && !self.advertisements.is_empty());
     }
 
     async fn start_advertising(&mut self, name: &str, uuids: &[Uuid]) -> Result<(), Error> {
-        let manufacturer_data = BTreeMap::new();
+        self.start_advertising_with(&AdvertisementConfig::new(name, uuids))
+            .await
+    }
 
-        let mut services: BTreeSet<Uuid> = BTreeSet::new();
-        for uuid in uuids {
-            services.insert(*uuid);
-        }
+    async fn start_advertising_with(&mut self, config: &AdvertisementConfig) -> Result<(), Error> {
+        self.advertise(AdvertisementId::PRIMARY, config).await
+    }
 
-        let le_advertisement = Advertisement {
-            service_uuids: services,
-            manufacturer_data,
-            discoverable: Some(true),
-            local_name: Some(name.to_string()),
-            ..Default::default()
-        };
-        let adv_handle: AdvertisementHandle = self.adapter.advertise(le_advertisement).await?;
+    async fn stop_advertising(&mut self) -> Result<(), Error> {
+        self.advertisements.clear();
+        Ok(())
+    }
 
-        let (handlers, services) = parse_services(self.services.clone(), self.sender_tx.clone());
+    // BlueZ reports the instances still available, the active ones count towards the total
+    async fn supported_advertising_instances(&mut self) -> Result<u8, Error> {
+        let active = self.adapter.active_advertising_instances().await?;
+        let available = self.adapter.supported_advertising_instances().await?;
+        Ok(active.saturating_add(available))
+    }
 
-        let app_handle = self
-            .adapter
-            .serve_gatt_application(Application {
-                services,
-                ..Default::default()
-            })
-            .await?;
+    async fn add_advertisement(
+        &mut self,
+        config: &AdvertisementConfig,
+    ) -> Result<AdvertisementId, Error> {
+        let advertisement = self.last_advertisement.next();
+        self.advertise(advertisement, config).await?;
+        self.last_advertisement = advertisement;
+        Ok(advertisement)
+    }
 
-        self.setup_char_handlers(handlers);
+    async fn update_advertisement(
+        &mut self,
+        advertisement: AdvertisementId,
+        config: &AdvertisementConfig,
+    ) -> Result<(), Error> {
+        if !self.advertisements.contains_key(&advertisement) {
+            return Err(Error::from_string(
+                format!("Advertisement {advertisement:?} not found"),
+                ErrorType::AdvertisementNotFound,
+            ));
+        }
+        self.advertise(advertisement, config).await
+    }
 
-        self.adv_handle = Some(adv_handle);
-        self.app_handle = Some(app_handle);
+    async fn remove_advertisement(&mut self, advertisement: AdvertisementId) -> Result<(), Error> {
+        if self.advertisements.remove(&advertisement).is_none() {
+            return Err(Error::from_string(
+                format!("Advertisement {advertisement:?} not found"),
+                ErrorType::AdvertisementNotFound,
+            ));
+        }
         Ok(())
     }
 
-    async fn stop_advertising(&mut self) -> Result<(), Error> {
-        self.adv_handle = None;
+    async fn is_serving(&mut self) -> Result<bool, Error> {
+        return Ok(self.app_handle.is_some());
+    }
+
+    async fn start_serving(&mut self) -> Result<(), Error> {
+        if self.app_handle.is_none() {
+            self.serve_gatt_application().await?;
+        }
+        Ok(())
+    }
+
+    async fn stop_serving(&mut self) -> Result<(), Error> {
         self.app_handle = None;
+        self.handle_controls.clear();
         Ok(())
     }
 
     async fn add_service(&mut self, service: &service::Service) -> Result<(), Error> {
-        self.services.push(service.clone());
-        Ok(())
+        service.validate(&self.services)?;
+        if !service.includes.is_empty() {
+            return Err(Error::from_string(
+                "bluer cannot register included services".to_string(),
+                ErrorType::NotSupported,
+            ));
+        }
+        let mut services = self.services.clone();
+        services.push(service.clone());
+        self.replace_services(services).await
+    }
+
+    async fn remove_service(&mut self, service: Uuid) -> Result<(), Error> {
+        let mut services = self.services.clone();
+        services.retain(|s| s.uuid != service);
+        if services.len() == self.services.len() {
+            return Err(Error::from_string(
+                format!("Service {service} not registered"),
+                ErrorType::ServiceNotFound,
+            ));
+        }
+        self.replace_services(services).await
     }
 
     async fn update_characteristic(
         &mut self,
-        characteristic: Uuid,
+        characteristic: CharacteristicId,
         value: Vec<u8>,
     ) -> Result<(), Error> {
-        let writers = match self.writers.lock() {
-            Ok(w) => w,
-            Err(err) => return Err(Error::from_string(err.to_string(), ErrorType::Bluez)),
-        };
-        let writer = writers.get(&characteristic).cloned();
-        drop(writers);
-        tokio::spawn(async move {
-            if let Some(writer) = writer {
-                if let Err(err) = writer.send(&value).await {
-                    log::error!("Error sending value {err:?}")
+        self.value_store.update(characteristic, &value);
+        if let Some(session) = self.get_indication_session(characteristic)? {
+            tokio::spawn(async move {
+                let status = indicate(session, value).await;
+                if status != NotificationStatus::Confirmed {
+                    log::error!("Error indicating {characteristic:?}: {status:?}");
                 }
-            }
-        });
+            });
+            return Ok(());
+        }
+        let writers = self.get_writers(characteristic, None)?;
+        send_to_writers(writers, value);
         Ok(())
     }
+
+    // bluetoothd handles indication confirmations of acquired notify sockets itself,
+    // so indications are reported as sent. Only indicate-only characteristics, served
+    // through a session shared by all subscribers, wait for the confirmation.
+    async fn update_characteristic_with_results(
+        &mut self,
+        characteristic: CharacteristicId,
+        value: Vec<u8>,
+        clients: Option<&[String]>,
+    ) -> Result<Vec<NotificationResult>, Error> {
+        self.value_store.update(characteristic, &value);
+        if let Some(session) = self.get_indication_session(characteristic)? {
+            if clients.is_some() {
+                return Err(Error::from_string(
+                    format!("BlueZ indicates {characteristic:?} to all subscribed clients at once"),
+                    ErrorType::NotSupported,
+                ));
+            }
+            // bluetoothd does not tell which clients the session serves
+            return Ok(vec![NotificationResult {
+                client: String::new(),
+                status: indicate(session, value).await,
+            }]);
+        }
+
+        let writers = self.get_writers(characteristic, clients)?;
+        let mut results: Vec<NotificationResult> = writers
+            .iter()
+            .map(|writer| NotificationResult {
+                client: writer.device_address().to_string(),
+                status: match writer.try_send(&value) {
+                    Ok(_) => NotificationStatus::Sent,
+                    Err(err) => io_error_to_status(err),
+                },
+            })
+            .collect();
+
+        for client in clients.unwrap_or_default() {
+            if !results.iter().any(|result| result.client == *client) {
+                results.push(NotificationResult {
+                    client: client.clone(),
+                    status: NotificationStatus::ClientGone,
+                });
+            }
+        }
+        Ok(results)
+    }
+
+    // Until served, the attributes are listed with their fixed handles
+    async fn attribute_table(&mut self) -> Result<Vec<Attribute>, Error> {
+        let mut attributes: Vec<Attribute> =
+            self.services.iter().flat_map(service_attributes).collect();
+        for (attribute, control) in attributes.iter_mut().zip(self.handle_controls.iter()) {
+            attribute.handle = control.handle().or(attribute.handle);
+        }
+        if attributes
+            .iter()
+            .all(|attribute| attribute.handle.is_some())
+        {
+            attributes.sort_by_key(|attribute| attribute.handle);
+        }
+        Ok(attributes)
+    }
+
+    async fn client_mtu(&mut self, client: &str) -> Result<Option<u16>, Error> {
+        Ok(self.client_mtus.get(client))
+    }
+
+    async fn connected_clients(&mut self) -> Result<Vec<String>, Error> {
+        let mut clients: Vec<String> = Vec::new();
+        for address in self.adapter.device_addresses().await? {
+            // Devices may be removed in the meantime
+            let connected = match self.adapter.device(address) {
+                Ok(device) => device.is_connected().await.unwrap_or_default(),
+                Err(_) => false,
+            };
+            if connected {
+                clients.push(address.to_string());
+            }
+        }
+        Ok(clients)
+    }
+
+    async fn disconnect_client(&mut self, client: &str) -> Result<(), Error> {
+        let device = client
+            .parse::<Address>()
+            .ok()
+            .and_then(|address| self.adapter.device(address).ok());
+        match device {
+            Some(device) if device.is_connected().await.unwrap_or_default() => {
+                Ok(device.disconnect().await?)
+            }
+            _ => Err(Error::from_string(
+                format!("Client {client} not connected"),
+                ErrorType::ClientNotFound,
+            )),
+        }
+    }
+
+    fn value_store(&self) -> ValueStore {
+        self.value_store.clone()
+    }
+
+    fn long_reads(&self) -> LongReads {
+        self.long_reads.clone()
+    }
+
+    fn prepared_writes(&self) -> PreparedWrites {
+        self.prepared_writes.clone()
+    }
+
+    fn response_timeout(&self) -> ResponseTimeout {
+        self.response_timeout.clone()
+    }
 }
 
 impl Peripheral {
+    // BlueZ cannot change a registered advertisement, so the previous one is replaced
+    async fn advertise(
+        &mut self,
+        advertisement: AdvertisementId,
+        config: &AdvertisementConfig,
+    ) -> Result<(), Error> {
+        // BlueZ always sends the local name in the scan response and everything else in the
+        // advertising data, check that layout to fail with a clearer error than BlueZ does
+        if let Some(field) = config
+            .scan_response
+            .iter()
+            .find(|field| **field != AdvertisementField::LocalName)
+        {
+            return Err(Error::from_string(
+                format!("BlueZ cannot send {field:?} in the scan response"),
+                ErrorType::NotSupported,
+            ));
+        }
+        AdvertisementConfig {
+            scan_response: vec![AdvertisementField::LocalName],
+            ..config.clone()
+        }
+        .encode()?;
+
+        // Register the replacement first, so a rejected config leaves the running one in place
+        let le_advertisement = to_bluer_advertisement(config);
+        let adv_handle: AdvertisementHandle = self.adapter.advertise(le_advertisement).await?;
+
+        // Clients connecting through the advertisement expect the services to be there
+        if config.connectable && self.app_handle.is_none() {
+            self.serve_gatt_application().await?;
+        }
+
+        // Dropping the replaced handle unregisters the old advertisement
+        self.advertisements.insert(advertisement, adv_handle);
+        Ok(())
+    }
+
+    // Serve `services` in place of the current ones, which are kept when BlueZ rejects them
+    async fn replace_services(&mut self, services: Vec<service::Service>) -> Result<(), Error> {
+        let previous = std::mem::replace(&mut self.services, services);
+        if self.app_handle.is_none() {
+            return Ok(());
+        }
+        let Err(err) = self.serve_gatt_application().await else {
+            return Ok(());
+        };
+        self.services = previous;
+        if self.app_handle.is_none() {
+            if let Err(err) = self.serve_gatt_application().await {
+                log::error!("Error serving the previous services again: {err:?}");
+            }
+        }
+        Err(err)
+    }
+
+    // Register the services as a GATT application, replacing the running one.
+    // bluetoothd indicates Service Changed to connected clients when the database changes.
+    // The new application is registered next to the running one, which is only dropped once
+    // BlueZ accepted it. Fixed handles would clash with the running one, so then it goes first.
+    async fn serve_gatt_application(&mut self) -> Result<(), Error> {
+        let has_fixed_handles = self.services.iter().any(|service| {
+            service_attributes(service)
+                .iter()
+                .any(|attribute| attribute.handle.is_some())
+        });
+        if has_fixed_handles {
+            self.app_handle = None;
+            self.handle_controls.clear();
+        }
+
+        let context = GattContext {
+            sender_tx: self.sender_tx.clone(),
+            value_store: self.value_store.clone(),
+            long_reads: self.long_reads.clone(),
+            prepared_writes: self.prepared_writes.clone(),
+            response_timeout: self.response_timeout.clone(),
+            client_mtus: self.client_mtus.clone(),
+            indications: self.indications.clone(),
+        };
+        let (handlers, services, handle_controls) = parse_services(self.services.clone(), &context);
+
+        let app_handle = self
+            .adapter
+            .serve_gatt_application(Application {
+                services,
+                ..Default::default()
+            })
+            .await?;
+
+        self.setup_char_handlers(handlers);
+
+        // Dropping the previous handle unregisters its application
+        self.app_handle = Some(app_handle);
+        self.handle_controls = handle_controls;
+        Ok(())
+    }
+
+    // Writers of the subscribed clients, or only of the given ones
+    fn get_writers(
+        &self,
+        characteristic: CharacteristicId,
+        clients: Option<&[String]>,
+    ) -> Result<Vec<Arc<CharacteristicWriter>>, Error> {
+        let writers = match self.writers.lock() {
+            Ok(w) => w,
+            Err(err) => return Err(Error::from_string(err.to_string(), ErrorType::Bluez)),
+        };
+        Ok(writers
+            .get(&characteristic)
+            .map(|subscribers| {
+                subscribers
+                    .iter()
+                    .filter(|(address, _)| {
+                        clients.is_none_or(|clients| clients.contains(&address.to_string()))
+                    })
+                    .map(|(_, writer)| writer.clone())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    // Session of an indicate-only characteristic, while clients are subscribed to it
+    fn get_indication_session(
+        &self,
+        characteristic: CharacteristicId,
+    ) -> Result<Option<Arc<IndicationSession>>, Error> {
+        match self.indications.lock() {
+            Ok(sessions) => Ok(sessions.get(&characteristic).cloned()),
+            Err(err) => Err(Error::from_string(err.to_string(), ErrorType::Bluez)),
+        }
+    }
+
     // Handle Characteristic Subscriptions
     fn setup_char_handlers(&mut self, handlers: Vec<CharNotifyHandler>) {
-        for mut handler in handlers {
+        for handler in handlers {
             let sender_tx = self.sender_tx.clone();
             let writers = self.writers.clone();
+            let client_mtus = self.client_mtus.clone();
 
             tokio::spawn(async move {
-                while let Some(CharacteristicControlEvent::Notify(writer)) =
-                    handler.control.next().await
-                {
-                    let writer = Arc::new(writer);
-
-                    let peripheral_request = PeripheralRequest {
-                        client: writer.device_address().to_string(),
-                        service: handler.service_uuid,
-                        characteristic: handler.characteristic_uuid,
-                    };
-
-                    if let Err(err) = sender_tx
-                        .send(PeripheralEvent::CharacteristicSubscriptionUpdate {
-                            request: peripheral_request.clone(),
-                            subscribed: true,
-                        })
-                        .await
-                    {
-                        log::error!("Error sending read request event: {:?}", err);
-                    }
+                // Poll under the lock so the handle table can read the control in between
+                let next_event = || {
+                    future::poll_fn(|cx| match handler.control.lock() {
+                        Ok(mut control) => control.poll_next_unpin(cx),
+                        Err(_) => Poll::Ready(None),
+                    })
+                };
+                while let Some(CharacteristicControlEvent::Notify(writer)) = next_event().await {
+                    // Every subscribed client gets its own writer, track them concurrently
+                    tokio::spawn(track_writer(
+                        Arc::new(writer),
+                        handler.service_uuid,
+                        handler.characteristic_uuid,
+                        sender_tx.clone(),
+                        writers.clone(),
+                        client_mtus.clone(),
+                    ));
+                }
+            });
+        }
+    }
+}
 
-                    if let Ok(mut writers_lock) = writers.lock() {
-                        writers_lock.insert(handler.characteristic_uuid, writer.clone());
-                    } else {
-                        log::error!("Failed to lock writers for adding a writer");
-                    }
+fn send_to_writers(writers: Vec<Arc<CharacteristicWriter>>, value: Vec<u8>) {
+    for writer in writers {
+        let value = value.clone();
+        tokio::spawn(async move {
+            if let Err(err) = writer.send(&value).await {
+                log::error!(
+                    "Error sending value to {}: {err:?}",
+                    writer.device_address()
+                )
+            }
+        });
+    }
+}
 
-                    if let Err(err) = writer.closed().await {
-                        log::error!("NotifyClosedErr {err:?}");
-                    }
+// Wait for the confirmation of the subscribed clients
+async fn indicate(session: Arc<IndicationSession>, value: Vec<u8>) -> NotificationStatus {
+    match session.0.lock().await.notify(value).await {
+        Ok(()) => NotificationStatus::Confirmed,
+        Err(err) if err.kind == bluer::ErrorKind::NotificationSessionStopped => {
+            NotificationStatus::ClientGone
+        }
+        Err(err) => NotificationStatus::Failed(err.to_string()),
+    }
+}
 
-                    if let Ok(mut writers_lock) = writers.lock() {
-                        writers_lock.remove(&handler.characteristic_uuid);
-                    } else {
-                        log::error!("Failed to lock writers for removing a writer");
-                    }
+fn io_error_to_status(err: std::io::Error) -> NotificationStatus {
+    match err.kind() {
+        ErrorKind::WouldBlock => NotificationStatus::QueueFull,
+        ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::NotConnected => {
+            NotificationStatus::ClientGone
+        }
+        _ => NotificationStatus::Failed(err.to_string()),
+    }
+}
 
-                    if let Err(err) = sender_tx
-                        .send(PeripheralEvent::CharacteristicSubscriptionUpdate {
-                            request: peripheral_request,
-                            subscribed: false,
-                        })
-                        .await
-                    {
-                        log::error!("Error sending read request event: {:?}", err);
-                    }
-                }
-            });
+// Keep the writer registered for its client until the client unsubscribes or disconnects
+async fn track_writer(
+    writer: Arc<CharacteristicWriter>,
+    service_uuid: Uuid,
+    characteristic_uuid: Uuid,
+    sender_tx: Sender<PeripheralEvent>,
+    writers: Writers,
+    client_mtus: ClientMtus,
+) {
+    let client = writer.device_address();
+    let characteristic = CharacteristicId::new(service_uuid, characteristic_uuid);
+    // bluer reports the ATT MTU less the 5 bytes it holds back to work around BlueZ
+    // dropping the end of full-sized packets
+    let mtu = u16::try_from(writer.mtu() + 5).ok();
+    client_mtus.update(&client.to_string(), mtu);
+    let peripheral_request = PeripheralRequest {
+        client: client.to_string(),
+        service: service_uuid,
+        characteristic: characteristic_uuid,
+        mtu,
+        link: None,
+    };
+
+    if let Err(err) = sender_tx
+        .send(PeripheralEvent::CharacteristicSubscriptionUpdate {
+            request: peripheral_request.clone(),
+            subscribed: true,
+        })
+        .await
+    {
+        log::error!("Error sending subscription event: {:?}", err);
+    }
+
+    if let Ok(mut writers_lock) = writers.lock() {
+        writers_lock
+            .entry(characteristic)
+            .or_default()
+            .insert(client, writer.clone());
+    } else {
+        log::error!("Failed to lock writers for adding a writer");
+    }
+
+    if let Err(err) = writer.closed().await {
+        log::error!("NotifyClosedErr {err:?}");
+    }
+
+    if let Ok(mut writers_lock) = writers.lock() {
+        if let Some(clients) = writers_lock.get_mut(&characteristic) {
+            // The client may have re-subscribed in the meantime with a new writer
+            if clients
+                .get(&client)
+                .is_some_and(|current| Arc::ptr_eq(current, &writer))
+            {
+                clients.remove(&client);
+            }
+            if clients.is_empty() {
+                writers_lock.remove(&characteristic);
+            }
+        }
+    } else {
+        log::error!("Failed to lock writers for removing a writer");
+    }
+
+    if let Err(err) = sender_tx
+        .send(PeripheralEvent::CharacteristicSubscriptionUpdate {
+            request: peripheral_request,
+            subscribed: false,
+        })
+        .await
+    {
+        log::error!("Error sending subscription event: {:?}", err);
+    }
+}
+
+// Report the connections of a device to the adapter until bluetoothd removes it,
+// starting with the one it is in already. Returns the address of the device once done.
+async fn watch_device(
+    device: Device,
+    sender_tx: Sender<PeripheralEvent>,
+    client_mtus: ClientMtus,
+    long_reads: LongReads,
+) -> Address {
+    let Ok(mut device_events) = device.events().await else {
+        return device.address();
+    };
+    let client = device.address().to_string();
+    let address_type = device.address_type().await.ok().map(to_address_type);
+
+    let mut connected = device.is_connected().await.unwrap_or_default();
+    if connected {
+        send_connection_event(&sender_tx, &client, address_type, true).await;
+    }
+
+    while let Some(DeviceEvent::PropertyChanged(property)) = device_events.next().await {
+        if let DeviceProperty::Connected(is_connected) = property {
+            if is_connected == connected {
+                continue;
+            }
+            connected = is_connected;
+            if !connected {
+                client_mtus.remove(&client);
+                long_reads.remove_client(&client);
+            }
+            send_connection_event(&sender_tx, &client, address_type, connected).await;
+        }
+    }
+    device.address()
+}
+
+async fn send_connection_event(
+    sender_tx: &Sender<PeripheralEvent>,
+    client: &str,
+    address_type: Option<AddressType>,
+    connected: bool,
+) {
+    let client = client.to_string();
+    let event = if connected {
+        PeripheralEvent::ClientConnected {
+            client,
+            address_type,
+        }
+    } else {
+        PeripheralEvent::ClientDisconnected {
+            client,
+            address_type,
         }
+    };
+    if let Err(err) = sender_tx.send(event).await {
+        log::error!("Error sending connection event: {:?}", err);
     }
 }
 
@@ -1,14 +1,18 @@
 use crate::gatt::{
     characteristic::Characteristic,
-    descriptor::Descriptor,
+    descriptor::{
+        Descriptor, EXTENDED_PROPERTIES_UUID, PRESENTATION_FORMAT_UUID, USER_DESCRIPTION_UUID,
+    },
     properties::{AttributePermission, CharacteristicProperty},
+    service::Service,
 };
 use objc2::{rc::Retained, runtime::AnyObject, ClassType};
 use objc2_core_bluetooth::{
     CBAttributePermissions, CBCharacteristicProperties, CBDescriptor, CBMutableCharacteristic,
     CBMutableDescriptor,
 };
-use objc2_foundation::{NSArray, NSData};
+use objc2_foundation::{NSArray, NSData, NSString};
+use uuid::Uuid;
 
 use super::mac_extensions::uuid_to_cbuuid;
 
@@ -28,9 +32,11 @@ pub fn parse_characteristic(characteristic: &Characteristic) -> Retained<CBMutab
                 acc | permission.clone().to_attribute_permission()
             });
 
+        // CoreBluetooth only caches values of read-only characteristics, the delegate serves the rest
         let value_data = characteristic
             .value
             .as_ref()
+            .filter(|_| is_read_only(characteristic))
             .map(|value| NSData::from_vec(value.clone()));
 
         let mutable_char = CBMutableCharacteristic::initWithType_properties_value_permissions(
@@ -45,6 +51,7 @@ pub fn parse_characteristic(characteristic: &Characteristic) -> Retained<CBMutab
             characteristic
                 .descriptors
                 .iter()
+                .filter(|desc| is_supported_descriptor(desc))
                 .map(|desc| parse_descriptor(desc))
                 .collect(),
         );
@@ -57,17 +64,67 @@ pub fn parse_characteristic(characteristic: &Characteristic) -> Retained<CBMutab
     }
 }
 
+fn is_read_only(characteristic: &Characteristic) -> bool {
+    return characteristic.properties.iter().all(|property| {
+        *property == CharacteristicProperty::Read || *property == CharacteristicProperty::Broadcast
+    });
+}
+
+/// First descriptor of the service CoreBluetooth cannot create. Creating a descriptor other
+/// than a user description or presentation format raises an exception, aborting the process.
+pub fn find_unsupported_descriptor(service: &Service) -> Option<Uuid> {
+    service
+        .characteristics
+        .iter()
+        .flat_map(|characteristic| characteristic.descriptors.iter())
+        .map(|descriptor| descriptor.uuid)
+        .find(|uuid| {
+            ![
+                USER_DESCRIPTION_UUID,
+                PRESENTATION_FORMAT_UUID,
+                EXTENDED_PROPERTIES_UUID,
+            ]
+            .contains(uuid)
+        })
+}
+
+// CoreBluetooth does not allow extended properties on local characteristics
+fn is_supported_descriptor(descriptor: &Descriptor) -> bool {
+    if descriptor.uuid != EXTENDED_PROPERTIES_UUID {
+        return true;
+    }
+    log::warn!("CoreBluetooth does not support extended properties, the descriptor is ignored");
+    false
+}
+
+// CoreBluetooth serves descriptors itself, so only static values are supported
 pub fn parse_descriptor(descriptor: &Descriptor) -> Retained<CBDescriptor> {
+    if descriptor.value.is_none() {
+        log::warn!(
+            "Descriptor {} has no value, CoreBluetooth does not forward descriptor requests",
+            descriptor.uuid
+        );
+    }
     unsafe {
         let value_data = descriptor
             .value
             .as_ref()
             .map(|value| NSData::from_vec(value.clone()));
+        // The user description is expected as a string, every other value as data
+        let description = descriptor
+            .value
+            .as_ref()
+            .filter(|_| descriptor.uuid == USER_DESCRIPTION_UUID)
+            .map(|value| NSString::from_str(&String::from_utf8_lossy(value)));
+        let value = match &description {
+            Some(description) => Some(description as &AnyObject),
+            None => value_data.as_ref().map(|data| data as &AnyObject),
+        };
 
         return Retained::into_super(CBMutableDescriptor::initWithType_value(
             CBMutableDescriptor::alloc(),
             &uuid_to_cbuuid(descriptor.uuid),
-            value_data.as_ref().map(|data| data as &AnyObject),
+            value,
         ));
     }
 }
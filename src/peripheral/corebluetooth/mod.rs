@@ -1,15 +1,26 @@
 mod characteristic_utils;
-pub mod error;
 mod mac_extensions;
 mod mac_utils;
 pub mod peripheral_delegate;
 mod peripheral_manager;
 
 use crate::{
+    advertisement::{AdvertisementConfig, AdvertisementId},
     error::{Error, ErrorType},
-    gatt::{peripheral_event::PeripheralEvent, service::Service},
+    gatt::{
+        attribute::{service_attributes, Attribute},
+        characteristic::CharacteristicId,
+        client_mtu::ClientMtus,
+        long_read::LongReads,
+        peripheral_event::{NotificationResult, PeripheralEvent},
+        prepared_write::PreparedWrites,
+        response_timeout::ResponseTimeout,
+        service::Service,
+        value_store::ValueStore,
+    },
 };
 use async_trait::async_trait;
+use characteristic_utils::find_unsupported_descriptor;
 use peripheral_manager::{is_authorized, run_peripheral_thread, ManagerEvent};
 use tokio::sync::{mpsc::Sender, oneshot};
 use uuid::Uuid;
@@ -18,6 +29,15 @@ use super::PeripheralImpl;
 
 pub struct Peripheral {
     manager_tx: Sender<ManagerEvent>,
+    // CoreBluetooth runs a single advertisement at a time
+    advertisement: Option<AdvertisementId>,
+    last_advertisement: AdvertisementId,
+    services: Vec<Service>,
+    value_store: ValueStore,
+    long_reads: LongReads,
+    prepared_writes: PreparedWrites,
+    response_timeout: ResponseTimeout,
+    client_mtus: ClientMtus,
 }
 
 #[async_trait]
@@ -29,8 +49,31 @@ impl PeripheralImpl for Peripheral {
             return Err(Error::from_type(ErrorType::PermissionDenied));
         }
         let (manager_tx, manager_rx) = tokio::sync::mpsc::channel(256);
-        run_peripheral_thread(sender_tx, manager_rx);
-        Ok(Peripheral { manager_tx })
+        let value_store = ValueStore::default();
+        let long_reads = LongReads::default();
+        let prepared_writes = PreparedWrites::default();
+        let response_timeout = ResponseTimeout::default();
+        let client_mtus = ClientMtus::default();
+        run_peripheral_thread(
+            sender_tx,
+            value_store.clone(),
+            long_reads.clone(),
+            prepared_writes.clone(),
+            response_timeout.clone(),
+            client_mtus.clone(),
+            manager_rx,
+        );
+        Ok(Peripheral {
+            manager_tx,
+            advertisement: None,
+            last_advertisement: AdvertisementId::PRIMARY,
+            services: Vec::new(),
+            value_store,
+            long_reads,
+            prepared_writes,
+            response_timeout,
+            client_mtus,
+        })
     }
 
     async fn is_powered(&mut self) -> Result<bool, Error> {
@@ -50,26 +93,101 @@ impl PeripheralImpl for Peripheral {
     }
 
     async fn start_advertising(&mut self, name: &str, uuids: &[Uuid]) -> Result<(), Error> {
+        return self
+            .start_advertising_with(&AdvertisementConfig::new(name, uuids))
+            .await;
+    }
+
+    async fn start_advertising_with(&mut self, config: &AdvertisementConfig) -> Result<(), Error> {
+        return self.advertise(AdvertisementId::PRIMARY, config).await;
+    }
+
+    async fn stop_advertising(&mut self) -> Result<(), Error> {
         let (responder, responder_rx) = oneshot::channel();
         self.manager_tx
-            .send(ManagerEvent::StartAdvertising {
-                name: name.to_string(),
-                uuids: uuids.to_vec(),
-                responder,
-            })
+            .send(ManagerEvent::StopAdvertising { responder })
+            .await?;
+        responder_rx.await??;
+        self.advertisement = None;
+        return Ok(());
+    }
+
+    async fn supported_advertising_instances(&mut self) -> Result<u8, Error> {
+        return Ok(1);
+    }
+
+    async fn add_advertisement(
+        &mut self,
+        config: &AdvertisementConfig,
+    ) -> Result<AdvertisementId, Error> {
+        let advertisement = self.last_advertisement.next();
+        self.advertise(advertisement, config).await?;
+        self.last_advertisement = advertisement;
+        return Ok(advertisement);
+    }
+
+    async fn update_advertisement(
+        &mut self,
+        advertisement: AdvertisementId,
+        config: &AdvertisementConfig,
+    ) -> Result<(), Error> {
+        if self.advertisement != Some(advertisement) {
+            return Err(Error::from_string(
+                format!("Advertisement {advertisement:?} not found"),
+                ErrorType::AdvertisementNotFound,
+            ));
+        }
+        return self.advertise(advertisement, config).await;
+    }
+
+    async fn remove_advertisement(&mut self, advertisement: AdvertisementId) -> Result<(), Error> {
+        if self.advertisement != Some(advertisement) {
+            return Err(Error::from_string(
+                format!("Advertisement {advertisement:?} not found"),
+                ErrorType::AdvertisementNotFound,
+            ));
+        }
+        return self.stop_advertising().await;
+    }
+
+    async fn is_serving(&mut self) -> Result<bool, Error> {
+        let (responder, responder_rx) = oneshot::channel();
+        self.manager_tx
+            .send(ManagerEvent::IsServing { responder })
             .await?;
         return responder_rx.await?;
     }
 
-    async fn stop_advertising(&mut self) -> Result<(), Error> {
+    async fn start_serving(&mut self) -> Result<(), Error> {
         let (responder, responder_rx) = oneshot::channel();
         self.manager_tx
-            .send(ManagerEvent::StopAdvertising { responder })
+            .send(ManagerEvent::StartServing { responder })
+            .await?;
+        return responder_rx.await?;
+    }
+
+    async fn stop_serving(&mut self) -> Result<(), Error> {
+        let (responder, responder_rx) = oneshot::channel();
+        self.manager_tx
+            .send(ManagerEvent::StopServing { responder })
             .await?;
         return responder_rx.await?;
     }
 
     async fn add_service(&mut self, service: &Service) -> Result<(), Error> {
+        service.validate(&self.services)?;
+        if let Some(descriptor) = find_unsupported_descriptor(service) {
+            return Err(Error::from_string(
+                format!("CoreBluetooth cannot serve descriptor {descriptor}"),
+                ErrorType::NotSupported,
+            ));
+        }
+        if service_attributes(service)
+            .iter()
+            .any(|attribute| attribute.handle.is_some())
+        {
+            log::warn!("CoreBluetooth assigns all handles itself, fixed handles are ignored");
+        }
         let (responder, responder_rx) = oneshot::channel();
         self.manager_tx
             .send(ManagerEvent::AddService {
@@ -77,22 +195,132 @@ impl PeripheralImpl for Peripheral {
                 responder,
             })
             .await?;
-        return responder_rx.await?;
+        responder_rx.await??;
+        self.services.push(service.clone());
+        Ok(())
+    }
+
+    async fn remove_service(&mut self, service: Uuid) -> Result<(), Error> {
+        let (responder, responder_rx) = oneshot::channel();
+        self.manager_tx
+            .send(ManagerEvent::RemoveService { service, responder })
+            .await?;
+        responder_rx.await??;
+        self.services.retain(|s| s.uuid != service);
+        Ok(())
     }
 
     async fn update_characteristic(
         &mut self,
-        characteristic: Uuid,
+        characteristic: CharacteristicId,
         value: Vec<u8>,
     ) -> Result<(), Error> {
+        self.value_store.update(characteristic, &value);
         let (responder, responder_rx) = oneshot::channel();
         self.manager_tx
             .send(ManagerEvent::UpdateCharacteristic {
                 characteristic,
                 value,
+                clients: None,
+                responder,
+            })
+            .await?;
+        responder_rx.await??;
+        return Ok(());
+    }
+
+    async fn update_characteristic_with_results(
+        &mut self,
+        characteristic: CharacteristicId,
+        value: Vec<u8>,
+        clients: Option<&[String]>,
+    ) -> Result<Vec<NotificationResult>, Error> {
+        self.value_store.update(characteristic, &value);
+        let (responder, responder_rx) = oneshot::channel();
+        self.manager_tx
+            .send(ManagerEvent::UpdateCharacteristic {
+                characteristic,
+                value,
+                clients: clients.map(|clients| clients.to_vec()),
                 responder,
             })
             .await?;
         return responder_rx.await?;
     }
+
+    // CoreBluetooth does not report the handles of local attributes
+    async fn attribute_table(&mut self) -> Result<Vec<Attribute>, Error> {
+        Ok(self
+            .services
+            .iter()
+            .flat_map(service_attributes)
+            .map(|attribute| Attribute {
+                handle: None,
+                ..attribute
+            })
+            .collect())
+    }
+
+    async fn client_mtu(&mut self, client: &str) -> Result<Option<u16>, Error> {
+        Ok(self.client_mtus.get(client))
+    }
+
+    // CoreBluetooth does not expose the connections of the GATT server
+    async fn connected_clients(&mut self) -> Result<Vec<String>, Error> {
+        Err(Error::from_string(
+            "Connected clients are not reported by CoreBluetooth".to_string(),
+            ErrorType::NotSupported,
+        ))
+    }
+
+    async fn disconnect_client(&mut self, client: &str) -> Result<(), Error> {
+        Err(Error::from_string(
+            format!("Client {client} cannot be disconnected on CoreBluetooth"),
+            ErrorType::NotSupported,
+        ))
+    }
+
+    fn value_store(&self) -> ValueStore {
+        return self.value_store.clone();
+    }
+
+    fn long_reads(&self) -> LongReads {
+        return self.long_reads.clone();
+    }
+
+    fn prepared_writes(&self) -> PreparedWrites {
+        return self.prepared_writes.clone();
+    }
+
+    fn response_timeout(&self) -> ResponseTimeout {
+        return self.response_timeout.clone();
+    }
+}
+
+impl Peripheral {
+    async fn advertise(
+        &mut self,
+        advertisement: AdvertisementId,
+        config: &AdvertisementConfig,
+    ) -> Result<(), Error> {
+        if self
+            .advertisement
+            .is_some_and(|current| current != advertisement)
+        {
+            return Err(Error::from_string(
+                "CoreBluetooth supports a single advertisement".to_string(),
+                ErrorType::AdvertisingLimitReached,
+            ));
+        }
+        let (responder, responder_rx) = oneshot::channel();
+        self.manager_tx
+            .send(ManagerEvent::StartAdvertising {
+                config: config.clone(),
+                responder,
+            })
+            .await?;
+        responder_rx.await??;
+        self.advertisement = Some(advertisement);
+        return Ok(());
+    }
 }
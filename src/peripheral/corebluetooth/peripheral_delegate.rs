@@ -1,9 +1,17 @@
 use super::mac_extensions::UuidExtension;
 use crate::{
     error::{Error, ErrorType},
-    gatt::peripheral_event::{
-        PeripheralEvent, PeripheralRequest, ReadRequestResponse, RequestResponse,
-        WriteRequestResponse,
+    gatt::{
+        characteristic::CharacteristicId,
+        client_mtu::ClientMtus,
+        long_read::LongReads,
+        peripheral_event::{
+            LinkType, PeripheralEvent, PeripheralRequest, ReadRequestResponse, RequestResponse,
+            WriteRequestResponse,
+        },
+        prepared_write::PreparedWrites,
+        response_timeout::{response_runtime, ResponseTimeout},
+        value_store::ValueStore,
     },
 };
 use objc2::{declare_class, msg_send_id, mutability, rc::Retained, ClassType, DeclaredClass};
@@ -13,7 +21,7 @@ use objc2_core_bluetooth::{
 };
 use objc2_foundation::{NSArray, NSData, NSError, NSObject, NSObjectProtocol};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::Debug,
     sync::{Arc, Mutex},
 };
@@ -25,6 +33,19 @@ pub struct IVars {
     pub sender: Sender<PeripheralEvent>,
     pub services_resolver: Arc<Mutex<HashMap<Uuid, oneshot::Sender<Option<String>>>>>,
     pub advertisement_resolver: Arc<Mutex<Option<oneshot::Sender<Option<String>>>>>,
+    pub subscribed_centrals: Arc<Mutex<HashMap<String, SubscribedCentral>>>,
+    pub static_values: Arc<Mutex<HashMap<(Uuid, Uuid), Vec<u8>>>>,
+    pub value_store: ValueStore,
+    pub long_reads: LongReads,
+    pub prepared_writes: PreparedWrites,
+    pub response_timeout: ResponseTimeout,
+    pub client_mtus: ClientMtus,
+}
+
+// Centrals are kept while subscribed to any characteristic, so updates can target them
+pub struct SubscribedCentral {
+    pub central: Retained<CBCentral>,
+    pub characteristics: HashSet<CharacteristicId>,
 }
 
 declare_class!(
@@ -94,12 +115,14 @@ declare_class!(
                 if service.is_none() {
                     return;
                 }
+                let request = self.request(
+                    central,
+                    characteristic.service().unwrap().get_uuid(),
+                    characteristic.get_uuid(),
+                );
+                self.add_subscribed_central(central, request.characteristic_id());
                 self.send_event(PeripheralEvent::CharacteristicSubscriptionUpdate {
-                    request: PeripheralRequest {
-                        client: central.identifier().to_string(),
-                        service: characteristic.service().unwrap().get_uuid(),
-                        characteristic: characteristic.get_uuid(),
-                    },
+                    request,
                     subscribed: true,
                 });
             }
@@ -116,13 +139,15 @@ declare_class!(
             if service.is_none() {
                 return;
             }
+            let request = self.request(
+                central,
+                characteristic.service().unwrap().get_uuid(),
+                characteristic.get_uuid(),
+            );
+            self.remove_subscribed_central(central, request.characteristic_id());
 
             self.send_event(PeripheralEvent::CharacteristicSubscriptionUpdate {
-               request: PeripheralRequest {
-                    client: central.identifier().to_string(),
-                    service: characteristic.service().unwrap().get_uuid(),
-                    characteristic: characteristic.get_uuid(),
-                },
+                request,
                 subscribed: false,
             });
         }}
@@ -142,11 +167,11 @@ declare_class!(
                 let characteristic = request.characteristic();
 
                 self.send_read_request(
-                    PeripheralRequest{
-                        client: central.identifier().to_string(),
-                        service: characteristic.service().unwrap().get_uuid(),
-                        characteristic: characteristic.get_uuid(),
-                    },
+                    self.request(
+                        &central,
+                        characteristic.service().unwrap().get_uuid(),
+                        characteristic.get_uuid(),
+                    ),
                     manager,
                     request,
                 );
@@ -159,6 +184,10 @@ declare_class!(
             manager: &CBPeripheralManager,
             requests: &NSArray<CBATTRequest>,
         ){
+            if self.ivars().prepared_writes.is_enabled() {
+                self.send_prepared_writes(manager, requests);
+                return;
+            }
             for request in requests {
                 unsafe{
                     let service = request.characteristic().service();
@@ -173,11 +202,11 @@ declare_class!(
                     let characteristic = request.characteristic();
 
                     self.send_write_request(
-                        PeripheralRequest{
-                             client: central.identifier().to_string(),
-                            service: characteristic.service().unwrap().get_uuid(),
-                            characteristic: characteristic.get_uuid(),
-                        },
+                        self.request(
+                            &central,
+                            characteristic.service().unwrap().get_uuid(),
+                            characteristic.get_uuid(),
+                        ),
                         manager,
                         request,
                         value,
@@ -189,11 +218,25 @@ declare_class!(
 );
 
 impl PeripheralDelegate {
-    pub fn new(sender: Sender<PeripheralEvent>) -> Retained<PeripheralDelegate> {
+    pub fn new(
+        sender: Sender<PeripheralEvent>,
+        value_store: ValueStore,
+        long_reads: LongReads,
+        prepared_writes: PreparedWrites,
+        response_timeout: ResponseTimeout,
+        client_mtus: ClientMtus,
+    ) -> Retained<PeripheralDelegate> {
         let this = PeripheralDelegate::alloc().set_ivars(IVars {
             sender,
             services_resolver: Arc::new(Mutex::new(HashMap::new())),
             advertisement_resolver: Arc::new(Mutex::new(None)),
+            subscribed_centrals: Arc::new(Mutex::new(HashMap::new())),
+            static_values: Arc::new(Mutex::new(HashMap::new())),
+            value_store,
+            long_reads,
+            prepared_writes,
+            response_timeout,
+            client_mtus,
         });
         return unsafe { msg_send_id![super(this), init] };
     }
@@ -251,6 +294,98 @@ impl PeripheralDelegate {
         return self.resolve_event(event);
     }
 
+    /// Serve reads of the characteristic from `value` instead of sending read requests
+    pub fn set_static_value(&self, service: Uuid, characteristic: Uuid, value: Option<Vec<u8>>) {
+        if let Ok(mut static_values) = self.ivars().static_values.lock() {
+            match value {
+                Some(value) => static_values.insert((service, characteristic), value),
+                None => static_values.remove(&(service, characteristic)),
+            };
+        }
+    }
+
+    pub fn remove_static_values(&self, service: Uuid) {
+        if let Ok(mut static_values) = self.ivars().static_values.lock() {
+            static_values.retain(|(value_service, _), _| *value_service != service);
+        }
+    }
+
+    fn get_static_value(&self, service: Uuid, characteristic: Uuid) -> Option<Vec<u8>> {
+        if let Ok(static_values) = self.ivars().static_values.lock() {
+            return static_values.get(&(service, characteristic)).cloned();
+        }
+        return None;
+    }
+
+    /// Subscribed centrals of the characteristic, optionally only the given clients
+    pub fn get_subscribed_centrals(
+        &self,
+        characteristic: CharacteristicId,
+        clients: Option<&[String]>,
+    ) -> Vec<(String, Retained<CBCentral>)> {
+        if let Ok(centrals) = self.ivars().subscribed_centrals.lock() {
+            return centrals
+                .iter()
+                .filter(|(client, subscribed)| {
+                    subscribed.characteristics.contains(&characteristic)
+                        && clients.is_none_or(|clients| clients.contains(client))
+                })
+                .map(|(client, subscribed)| (client.clone(), subscribed.central.clone()))
+                .collect();
+        }
+        return Vec::new();
+    }
+
+    fn add_subscribed_central(&self, central: &CBCentral, characteristic: CharacteristicId) {
+        let identifier = unsafe { central.identifier() }.to_string();
+        if let Ok(mut centrals) = self.ivars().subscribed_centrals.lock() {
+            centrals
+                .entry(identifier)
+                .or_insert_with(|| SubscribedCentral {
+                    central: central.retain(),
+                    characteristics: HashSet::new(),
+                })
+                .characteristics
+                .insert(characteristic);
+        }
+    }
+
+    // CoreBluetooth reports the longest value an update can carry, which is the MTU minus 3
+    fn central_mtu(&self, central: &CBCentral) -> Option<u16> {
+        let mtu = u16::try_from(unsafe { central.maximumUpdateValueLength() } + 3).ok();
+        self.ivars()
+            .client_mtus
+            .update(&unsafe { central.identifier() }.to_string(), mtu);
+        mtu
+    }
+
+    fn request(
+        &self,
+        central: &CBCentral,
+        service: Uuid,
+        characteristic: Uuid,
+    ) -> PeripheralRequest {
+        PeripheralRequest {
+            client: unsafe { central.identifier() }.to_string(),
+            service,
+            characteristic,
+            mtu: self.central_mtu(central),
+            link: Some(LinkType::Le),
+        }
+    }
+
+    fn remove_subscribed_central(&self, central: &CBCentral, characteristic: CharacteristicId) {
+        let identifier = unsafe { central.identifier() }.to_string();
+        if let Ok(mut centrals) = self.ivars().subscribed_centrals.lock() {
+            if let Some(subscribed) = centrals.get_mut(&identifier) {
+                subscribed.characteristics.remove(&characteristic);
+                if subscribed.characteristics.is_empty() {
+                    centrals.remove(&identifier);
+                }
+            }
+        }
+    }
+
     fn resolve_event(
         &self,
         event: Result<
@@ -261,16 +396,10 @@ impl PeripheralDelegate {
         let event = match event {
             Ok(Ok(event)) => event,
             Ok(Err(e)) => {
-                return Err(Error::from_string(
-                    format!("Channel error while waiting: {}", e),
-                    ErrorType::CoreBluetooth,
-                ));
+                return Err(Error::from_source(e, ErrorType::ChannelError));
             }
-            Err(_) => {
-                return Err(Error::from_string(
-                    "Timeout waiting for event".to_string(),
-                    ErrorType::CoreBluetooth,
-                ));
+            Err(e) => {
+                return Err(Error::from_source(e, ErrorType::Timeout));
             }
         };
 
@@ -299,31 +428,60 @@ impl PeripheralDelegate {
         manager: &CBPeripheralManager,
         request: &CBATTRequest,
     ) {
-        let sender = self.ivars().sender.clone();
-        unsafe {
-            futures::executor::block_on(async {
-                let (resp_tx, resp_rx) = oneshot::channel::<ReadRequestResponse>();
-
-                if let Err(e) = sender
-                    .send(PeripheralEvent::ReadRequest {
-                        request: peripheral_request,
-                        offset: request.offset() as u64,
-                        responder: resp_tx,
-                    })
-                    .await
-                {
-                    log::error!("Error sending delegate event: {}", e);
+        if let Some(value) = self.get_static_value(
+            peripheral_request.service,
+            peripheral_request.characteristic,
+        ) {
+            unsafe {
+                let offset = request.offset();
+                if offset > value.len() {
+                    manager.respondToRequest_withResult(request, CBATTError::InvalidOffset);
                     return;
                 }
+                request.setValue(Some(&NSData::from_vec(value[offset..].to_vec())));
+                manager.respondToRequest_withResult(request, CBATTError::Success);
+            }
+            return;
+        }
+
+        let offset = unsafe { request.offset() as u64 };
+        let characteristic = peripheral_request.characteristic_id();
+        let stored = self.ivars().value_store.read(characteristic, offset);
+        let long_reads = self.ivars().long_reads.clone();
+        let client = peripheral_request.client.clone();
+        let mtu = peripheral_request.mtu;
+        if let Some(result) =
+            stored.or_else(|| long_reads.read(&client, characteristic, offset, mtu))
+        {
+            respond_to_read(manager, request, result);
+            return;
+        }
+
+        let response_timeout = self.ivars().response_timeout.clone();
+        let (resp_tx, resp_rx) = oneshot::channel::<ReadRequestResponse>();
+        if !self.send_request_event(PeripheralEvent::ReadRequest {
+            request: peripheral_request.clone(),
+            offset: long_reads.request_offset(offset),
+            responder: resp_tx,
+        }) {
+            return;
+        }
 
-                let mut cb_att_error = CBATTError::InvalidHandle;
-                if let Ok(result) = resp_rx.await {
-                    cb_att_error = result.response.to_cb_error();
-                    request.setValue(Some(&NSData::from_vec(result.value)));
+        let sender = self.ivars().sender.clone();
+        let pending = PendingResponse::new(manager, request);
+        response_runtime().spawn(async move {
+            let result = match response_timeout
+                .wait(&sender, peripheral_request, None, resp_rx)
+                .await
+            {
+                Ok(result) if result.response == RequestResponse::Success => {
+                    long_reads.respond(&client, characteristic, offset, mtu, result.value)
                 }
-                manager.respondToRequest_withResult(request, cb_att_error);
-            });
-        };
+                Ok(result) => Err(result.response),
+                Err(response) => Err(response),
+            };
+            pending.respond_read(result);
+        });
     }
 
     fn send_write_request(
@@ -333,43 +491,200 @@ impl PeripheralDelegate {
         request: &CBATTRequest,
         value: Vec<u8>,
     ) {
+        let characteristic = peripheral_request.characteristic_id();
+        let offset = unsafe { request.offset() as u64 };
+        let (resp_tx, resp_rx) = oneshot::channel::<WriteRequestResponse>();
+        if !self.send_request_event(PeripheralEvent::WriteRequest {
+            request: peripheral_request.clone(),
+            value: value.clone(),
+            offset,
+            responder: resp_tx,
+        }) {
+            return;
+        }
+
         let sender = self.ivars().sender.clone();
-        unsafe {
-            futures::executor::block_on(async {
-                let (resp_tx, resp_rx) = oneshot::channel::<WriteRequestResponse>();
+        let value_store = self.ivars().value_store.clone();
+        let response_timeout = self.ivars().response_timeout.clone();
+        let pending = PendingResponse::new(manager, request);
+        response_runtime().spawn(async move {
+            let cb_att_error = match response_timeout
+                .wait(&sender, peripheral_request, None, resp_rx)
+                .await
+            {
+                Ok(result) if result.response == RequestResponse::Success => {
+                    match value_store.write(characteristic, &value, offset) {
+                        Ok(()) => CBATTError::Success,
+                        Err(response) => response.to_cb_error(),
+                    }
+                }
+                Ok(result) => result.response.to_cb_error(),
+                Err(response) => response.to_cb_error(),
+            };
+            pending.respond(cb_att_error);
+        });
+    }
 
-                if let Err(e) = sender
-                    .send(PeripheralEvent::WriteRequest {
-                        request: peripheral_request,
-                        value,
-                        offset: request.offset() as u64,
-                        responder: resp_tx,
-                    })
-                    .await
-                {
-                    log::error!("Error sending delegate event: {}", e);
-                    return;
+    // Events are sent on the dispatch queue to keep them in order, only waiting for the
+    // responses moves to the response runtime, so a slow application does not hold up the queue
+    fn send_request_event(&self, event: PeripheralEvent) -> bool {
+        let sender = self.ivars().sender.clone();
+        if let Err(e) = futures::executor::block_on(sender.send(event)) {
+            log::error!("Error sending delegate event: {}", e);
+            return false;
+        }
+        true
+    }
+}
+
+impl PeripheralDelegate {
+    // CoreBluetooth hands over all requests of an executed queue at once and expects a single
+    // response on the first one, so the fragments are reassembled right away
+    fn send_prepared_writes(
+        &self,
+        manager: &CBPeripheralManager,
+        requests: &NSArray<CBATTRequest>,
+    ) {
+        let prepared_writes = self.ivars().prepared_writes.clone();
+        let mut first: Option<&CBATTRequest> = None;
+        let mut client = String::new();
+        let mut mtu: Option<u16> = None;
+        let mut result: Result<(), RequestResponse> = Ok(());
+        for request in requests {
+            unsafe {
+                let characteristic = request.characteristic();
+                let Some(service) = characteristic.service() else {
+                    continue;
+                };
+                if first.is_none() {
+                    first = Some(request);
+                    let central = request.central();
+                    client = central.identifier().to_string();
+                    mtu = self.central_mtu(&central);
                 }
+                let value = request
+                    .value()
+                    .map(|ns_data| ns_data.bytes().to_vec())
+                    .unwrap_or_default();
+                result = prepared_writes.prepare(
+                    &client,
+                    service.get_uuid(),
+                    characteristic.get_uuid(),
+                    request.offset() as u64,
+                    &value,
+                );
+            }
+            if result.is_err() {
+                break;
+            }
+        }
+        let Some(first) = first else {
+            return;
+        };
+        let writes = prepared_writes.take(&client, Duration::ZERO);
+        let pending = PendingResponse::new(manager, first);
+        if let Err(response) = result {
+            pending.respond(response.to_cb_error());
+            return;
+        }
 
-                let mut cb_att_error = CBATTError::InvalidHandle;
-                if let Ok(result) = resp_rx.await {
-                    cb_att_error = result.response.to_cb_error();
+        let sender = self.ivars().sender.clone();
+        let value_store = self.ivars().value_store.clone();
+        let response_timeout = self.ivars().response_timeout.clone();
+        response_runtime().spawn(async move {
+            let result: Result<(), RequestResponse> = async {
+                for write in writes {
+                    let (resp_tx, resp_rx) = oneshot::channel::<WriteRequestResponse>();
+                    let request = PeripheralRequest {
+                        client: client.clone(),
+                        service: write.service,
+                        characteristic: write.characteristic,
+                        mtu,
+                        link: Some(LinkType::Le),
+                    };
+                    sender
+                        .send(PeripheralEvent::WriteRequest {
+                            request: request.clone(),
+                            value: write.value.clone(),
+                            offset: 0,
+                            responder: resp_tx,
+                        })
+                        .await
+                        .map_err(|_| RequestResponse::UnlikelyError)?;
+                    let characteristic = request.characteristic_id();
+                    let res = response_timeout
+                        .wait(&sender, request, None, resp_rx)
+                        .await?;
+                    if res.response != RequestResponse::Success {
+                        return Err(res.response);
+                    }
+                    value_store.write(characteristic, &write.value, 0)?;
                 }
+                Ok(())
+            }
+            .await;
 
-                manager.respondToRequest_withResult(request, cb_att_error);
+            pending.respond(match result {
+                Ok(()) => CBATTError::Success,
+                Err(response) => response.to_cb_error(),
             });
+        });
+    }
+}
+
+// Request answered from the response runtime once the application responded
+struct PendingResponse {
+    manager: Retained<CBPeripheralManager>,
+    request: Retained<CBATTRequest>,
+}
+
+// CoreBluetooth accepts responses from any thread, and the dispatch queue does not touch
+// a request again after handing it over
+unsafe impl Send for PendingResponse {}
+
+impl PendingResponse {
+    fn new(manager: &CBPeripheralManager, request: &CBATTRequest) -> Self {
+        PendingResponse {
+            manager: manager.retain(),
+            request: request.retain(),
+        }
+    }
+
+    // Both take self, so the whole Send struct moves into the response future instead of
+    // its fields being captured one by one
+    fn respond(self, result: CBATTError) {
+        unsafe {
+            self.manager
+                .respondToRequest_withResult(&self.request, result)
         };
     }
+
+    fn respond_read(self, result: Result<Vec<u8>, RequestResponse>) {
+        respond_to_read(&self.manager, &self.request, result);
+    }
+}
+
+fn respond_to_read(
+    manager: &CBPeripheralManager,
+    request: &CBATTRequest,
+    result: Result<Vec<u8>, RequestResponse>,
+) {
+    unsafe {
+        match result {
+            Ok(value) => {
+                request.setValue(Some(&NSData::from_vec(value)));
+                manager.respondToRequest_withResult(request, CBATTError::Success);
+            }
+            Err(response) => {
+                manager.respondToRequest_withResult(request, response.to_cb_error());
+            }
+        }
+    }
 }
 
 impl RequestResponse {
+    // CBATTError values are the ATT error codes, which CoreBluetooth passes on as is
     fn to_cb_error(self) -> CBATTError {
-        match self {
-            RequestResponse::Success => CBATTError::Success,
-            RequestResponse::InvalidHandle => CBATTError::InvalidHandle,
-            RequestResponse::RequestNotSupported => CBATTError::RequestNotSupported,
-            RequestResponse::InvalidOffset => CBATTError::InvalidOffset,
-            RequestResponse::UnlikelyError => CBATTError::UnlikelyError,
-        }
+        CBATTError(self.code().into())
     }
 }
@@ -1,15 +1,22 @@
 use super::mac_utils;
 use super::peripheral_delegate::PeripheralDelegate;
 use super::{characteristic_utils::parse_characteristic, mac_extensions::uuid_to_cbuuid};
+use crate::advertisement::AdvertisementConfig;
 use crate::error::{Error, ErrorType};
-use crate::gatt::peripheral_event::PeripheralEvent;
+use crate::gatt::characteristic::CharacteristicId;
+use crate::gatt::client_mtu::ClientMtus;
+use crate::gatt::long_read::LongReads;
+use crate::gatt::peripheral_event::{NotificationResult, NotificationStatus, PeripheralEvent};
+use crate::gatt::prepared_write::PreparedWrites;
+use crate::gatt::response_timeout::ResponseTimeout;
 use crate::gatt::service::Service;
+use crate::gatt::value_store::ValueStore;
 use objc2::msg_send_id;
 use objc2::{rc::Retained, runtime::AnyObject, ClassType};
 use objc2_core_bluetooth::{
-    CBAdvertisementDataLocalNameKey, CBAdvertisementDataServiceUUIDsKey, CBCharacteristic,
-    CBManager, CBManagerAuthorization, CBManagerState, CBMutableCharacteristic, CBMutableService,
-    CBPeripheralManager,
+    CBAdvertisementDataLocalNameKey, CBAdvertisementDataServiceUUIDsKey, CBCentral,
+    CBCharacteristic, CBManager, CBManagerAuthorization, CBManagerState, CBMutableCharacteristic,
+    CBMutableService, CBPeripheralManager, CBService,
 };
 use objc2_foundation::{NSArray, NSData, NSDictionary, NSString};
 use once_cell::sync::OnceCell;
@@ -29,28 +36,49 @@ pub(crate) enum ManagerEvent {
         responder: oneshot::Sender<Result<bool, Error>>,
     },
     StartAdvertising {
-        name: String,
-        uuids: Vec<Uuid>,
+        config: AdvertisementConfig,
         responder: oneshot::Sender<Result<(), Error>>,
     },
     StopAdvertising {
         responder: oneshot::Sender<Result<(), Error>>,
     },
+    IsServing {
+        responder: oneshot::Sender<Result<bool, Error>>,
+    },
+    StartServing {
+        responder: oneshot::Sender<Result<(), Error>>,
+    },
+    StopServing {
+        responder: oneshot::Sender<Result<(), Error>>,
+    },
     AddService {
         service: Service,
         responder: oneshot::Sender<Result<(), Error>>,
     },
+    RemoveService {
+        service: Uuid,
+        responder: oneshot::Sender<Result<(), Error>>,
+    },
     UpdateCharacteristic {
-        characteristic: Uuid,
+        characteristic: CharacteristicId,
         value: Vec<u8>,
-        responder: oneshot::Sender<Result<(), Error>>,
+        clients: Option<Vec<String>>,
+        responder: oneshot::Sender<Result<Vec<NotificationResult>, Error>>,
     },
 }
 
 static PERIPHERAL_THREAD: OnceCell<()> = OnceCell::new();
 
 // Handle Peripheral Manager and all communication in a separate thread
-pub fn run_peripheral_thread(sender: Sender<PeripheralEvent>, listener: Receiver<ManagerEvent>) {
+pub fn run_peripheral_thread(
+    sender: Sender<PeripheralEvent>,
+    value_store: ValueStore,
+    long_reads: LongReads,
+    prepared_writes: PreparedWrites,
+    response_timeout: ResponseTimeout,
+    client_mtus: ClientMtus,
+    listener: Receiver<ManagerEvent>,
+) {
     PERIPHERAL_THREAD.get_or_init(|| {
         thread::spawn(move || {
             let runtime = runtime::Builder::new_current_thread().enable_time().build();
@@ -59,7 +87,15 @@ pub fn run_peripheral_thread(sender: Sender<PeripheralEvent>, listener: Receiver
                 return;
             }
             runtime.unwrap().block_on(async move {
-                let mut peripheral_manager = PeripheralManager::new(sender, listener);
+                let mut peripheral_manager = PeripheralManager::new(
+                    sender,
+                    value_store,
+                    long_reads,
+                    prepared_writes,
+                    response_timeout,
+                    client_mtus,
+                    listener,
+                );
                 loop {
                     peripheral_manager.handle_event().await;
                 }
@@ -73,12 +109,30 @@ struct PeripheralManager {
     manager_event: Receiver<ManagerEvent>,
     cb_peripheral_manager: Retained<CBPeripheralManager>,
     peripheral_delegate: Retained<PeripheralDelegate>,
-    cached_characteristics: HashMap<Uuid, Retained<CBMutableCharacteristic>>,
+    cached_characteristics: HashMap<CharacteristicId, Retained<CBMutableCharacteristic>>,
+    // In the order they were added, so included services are published before the others
+    cached_services: Vec<(Uuid, Retained<CBMutableService>)>,
+    is_serving: bool,
 }
 
 impl PeripheralManager {
-    fn new(sender_tx: mpsc::Sender<PeripheralEvent>, listener: Receiver<ManagerEvent>) -> Self {
-        let delegate: Retained<PeripheralDelegate> = PeripheralDelegate::new(sender_tx);
+    fn new(
+        sender_tx: mpsc::Sender<PeripheralEvent>,
+        value_store: ValueStore,
+        long_reads: LongReads,
+        prepared_writes: PreparedWrites,
+        response_timeout: ResponseTimeout,
+        client_mtus: ClientMtus,
+        listener: Receiver<ManagerEvent>,
+    ) -> Self {
+        let delegate: Retained<PeripheralDelegate> = PeripheralDelegate::new(
+            sender_tx,
+            value_store,
+            long_reads,
+            prepared_writes,
+            response_timeout,
+            client_mtus,
+        );
         let label: CString = CString::new("CBqueue").unwrap();
         let queue: *mut std::ffi::c_void = unsafe {
             mac_utils::dispatch_queue_create(label.as_ptr(), mac_utils::DISPATCH_QUEUE_SERIAL)
@@ -93,6 +147,8 @@ impl PeripheralManager {
             cb_peripheral_manager: peripheral_manager,
             peripheral_delegate: delegate,
             cached_characteristics: HashMap::new(),
+            cached_services: Vec::new(),
+            is_serving: false,
         }
     }
 
@@ -105,25 +161,37 @@ impl PeripheralManager {
                 ManagerEvent::IsAdvertising { responder } => {
                     let _ = responder.send(Ok(self.is_advertising()));
                 }
-                ManagerEvent::StartAdvertising {
-                    name,
-                    uuids,
-                    responder,
-                } => {
-                    let _ = responder.send(self.start_advertising(&name, &uuids).await);
+                ManagerEvent::StartAdvertising { config, responder } => {
+                    let _ = responder.send(self.start_advertising(&config).await);
                 }
                 ManagerEvent::StopAdvertising { responder } => {
                     let _ = responder.send(Ok(self.stop_advertising()));
                 }
+                ManagerEvent::IsServing { responder } => {
+                    let _ = responder.send(Ok(self.is_serving));
+                }
+                ManagerEvent::StartServing { responder } => {
+                    let _ = responder.send(self.start_serving().await);
+                }
+                ManagerEvent::StopServing { responder } => {
+                    let _ = responder.send(Ok(self.stop_serving()));
+                }
                 ManagerEvent::AddService { service, responder } => {
                     let _ = responder.send(self.add_service(&service).await);
                 }
+                ManagerEvent::RemoveService { service, responder } => {
+                    let _ = responder.send(self.remove_service(service));
+                }
                 ManagerEvent::UpdateCharacteristic {
                     characteristic,
                     value,
+                    clients,
                     responder,
                 } => {
-                    let _ = responder.send(self.update_characteristic(characteristic, value).await);
+                    let _ = responder.send(
+                        self.update_characteristic(characteristic, value, clients)
+                            .await,
+                    );
                 }
             };
         }
@@ -136,27 +204,56 @@ impl PeripheralManager {
         }
     }
 
-    async fn start_advertising(self: &Self, name: &str, uuids: &[Uuid]) -> Result<(), Error> {
+    // CoreBluetooth only lets apps advertise a local name and service uuids
+    async fn start_advertising(&mut self, config: &AdvertisementConfig) -> Result<(), Error> {
         if self
             .peripheral_delegate
             .is_waiting_for_advertisement_result()
         {
             return Err(Error::from_string(
                 "Already in progress".to_string(),
-                ErrorType::CoreBluetooth,
+                ErrorType::AlreadyInProgress,
             ));
         }
 
+        if !config.manufacturer_data.is_empty()
+            || !config.service_data.is_empty()
+            || !config.solicit_uuids.is_empty()
+            || config.appearance.is_some()
+            || config.tx_power.is_some()
+            || config.discoverable_timeout.is_some()
+            || config.min_interval.is_some()
+            || config.max_interval.is_some()
+            || !config.includes.is_empty()
+            || !config.connectable
+        {
+            log::warn!("CoreBluetooth only advertises the local name and service uuids");
+        }
+
+        // Its advertisements are always connectable, and clients expect the services to be there
+        self.start_serving().await?;
+
+        // Restart to replace the data of a running advertisement
+        if self.is_advertising() {
+            self.stop_advertising();
+        }
+
         let mut keys: Vec<&NSString> = vec![];
         let mut objects: Vec<Retained<AnyObject>> = vec![];
 
         unsafe {
-            keys.push(CBAdvertisementDataLocalNameKey);
-            objects.push(Retained::cast(NSString::from_str(name)));
+            if let Some(name) = &config.local_name {
+                keys.push(CBAdvertisementDataLocalNameKey);
+                objects.push(Retained::cast(NSString::from_str(name)));
+            }
 
             keys.push(CBAdvertisementDataServiceUUIDsKey);
             objects.push(Retained::cast(NSArray::from_vec(
-                uuids.iter().map(|u| uuid_to_cbuuid(u.clone())).collect(),
+                config
+                    .service_uuids
+                    .iter()
+                    .map(|u| uuid_to_cbuuid(u.clone()))
+                    .collect(),
             )));
         }
 
@@ -184,24 +281,94 @@ impl PeripheralManager {
         unsafe { self.cb_peripheral_manager.isAdvertising() }
     }
 
-    async fn update_characteristic(
-        &mut self,
-        characteristic: Uuid,
-        value: Vec<u8>,
-    ) -> Result<(), Error> {
-        if let Some(char) = self.cached_characteristics.get(&characteristic) {
+    // Services are published from here or an advertisement on, until serving is stopped
+    async fn start_serving(&mut self) -> Result<(), Error> {
+        if self.is_serving {
+            return Ok(());
+        }
+        self.is_serving = true;
+        for (uuid, mutable_service) in self.cached_services.clone() {
             unsafe {
-                self.cb_peripheral_manager
-                    .updateValue_forCharacteristic_onSubscribedCentrals(
-                        &NSData::from_vec(value.clone()),
-                        char,
-                        None,
-                    );
+                self.cb_peripheral_manager.addService(&mutable_service);
             }
+            self.peripheral_delegate.ensure_service_added(uuid).await?;
         }
         return Ok(());
     }
 
+    fn stop_serving(&mut self) {
+        unsafe {
+            self.cb_peripheral_manager.removeAllServices();
+        }
+        self.is_serving = false;
+    }
+
+    // CoreBluetooth confirms indications itself, so delivered updates are reported as sent
+    async fn update_characteristic(
+        &mut self,
+        characteristic: CharacteristicId,
+        value: Vec<u8>,
+        clients: Option<Vec<String>>,
+    ) -> Result<Vec<NotificationResult>, Error> {
+        let subscribed = self
+            .peripheral_delegate
+            .get_subscribed_centrals(characteristic, clients.as_deref());
+
+        let mut results: Vec<NotificationResult> = clients
+            .as_deref()
+            .unwrap_or_default()
+            .iter()
+            .filter(|client| !subscribed.iter().any(|(id, _)| id == *client))
+            .map(|client| NotificationResult {
+                client: client.clone(),
+                status: NotificationStatus::ClientGone,
+            })
+            .collect();
+
+        let Some(char) = self.cached_characteristics.get(&characteristic) else {
+            return Ok(results);
+        };
+        if clients.is_some() && subscribed.is_empty() {
+            return Ok(results);
+        }
+
+        // None notifies every subscribed central
+        let centrals: Option<Retained<NSArray<CBCentral>>> = clients.map(|_| {
+            NSArray::from_vec(
+                subscribed
+                    .iter()
+                    .map(|(_, central)| central.clone())
+                    .collect(),
+            )
+        });
+
+        let sent = unsafe {
+            self.cb_peripheral_manager
+                .updateValue_forCharacteristic_onSubscribedCentrals(
+                    &NSData::from_vec(value),
+                    char,
+                    centrals.as_deref(),
+                )
+        };
+
+        // A full transmit queue is reported through peripheralManagerIsReadyToUpdateSubscribers.
+        // CoreBluetooth handles indication confirmations without reporting them, so indications
+        // are never reported as confirmed.
+        let status = match sent {
+            true => NotificationStatus::Sent,
+            false => NotificationStatus::QueueFull,
+        };
+        results.extend(
+            subscribed
+                .into_iter()
+                .map(|(client, _)| NotificationResult {
+                    client,
+                    status: status.clone(),
+                }),
+        );
+        return Ok(results);
+    }
+
     // Peripheral with cache value must only have Read permission, else it will crash
     // TODO: throw proper error, or catch Objc errors
     async fn add_service(&mut self, service: &Service) -> Result<(), Error> {
@@ -211,38 +378,97 @@ impl PeripheralManager {
         {
             return Err(Error::from_string(
                 "Already in progress".to_string(),
-                ErrorType::CoreBluetooth,
+                ErrorType::AlreadyInProgress,
             ));
         }
 
-        unsafe {
-            let mut characteristics: Vec<Retained<CBCharacteristic>> = Vec::new();
-
-            for char in service.characteristics.iter() {
-                let cb_char = parse_characteristic(char);
-                characteristics.push(Retained::into_super(cb_char.clone()));
-                self.cached_characteristics.insert(char.uuid, cb_char);
-            }
+        let mut includes: Vec<Retained<CBService>> = Vec::new();
+        for include in service.includes.iter() {
+            let Some((_, included_service)) = self
+                .cached_services
+                .iter()
+                .find(|(uuid, _)| uuid == include)
+            else {
+                return Err(Error::from_string(
+                    format!("Included service {include} not registered"),
+                    ErrorType::ServiceNotFound,
+                ));
+            };
+            includes.push(Retained::into_super(included_service.clone()));
+        }
 
-            let mutable_service: Retained<CBMutableService> =
-                CBMutableService::initWithType_primary(
-                    CBMutableService::alloc(),
-                    &uuid_to_cbuuid(service.uuid),
-                    service.primary,
-                );
+        // Static values are in place before the service is published, the caches only take
+        // the service once CoreBluetooth confirmed it
+        let mut characteristics: Vec<Retained<CBCharacteristic>> = Vec::new();
+        let mut cb_characteristics: Vec<(CharacteristicId, Retained<CBMutableCharacteristic>)> =
+            Vec::new();
+        for char in service.characteristics.iter() {
+            self.peripheral_delegate
+                .set_static_value(service.uuid, char.uuid, char.value.clone());
+            let cb_char = parse_characteristic(char);
+            characteristics.push(Retained::into_super(cb_char.clone()));
+            cb_characteristics.push((CharacteristicId::new(service.uuid, char.uuid), cb_char));
+        }
 
+        let mutable_service: Retained<CBMutableService> = unsafe {
+            let mutable_service = CBMutableService::initWithType_primary(
+                CBMutableService::alloc(),
+                &uuid_to_cbuuid(service.uuid),
+                service.primary,
+            );
             if !characteristics.is_empty() {
                 let chars = NSArray::from_vec(characteristics);
                 mutable_service.setCharacteristics(Some(&chars));
             }
+            if !includes.is_empty() {
+                mutable_service.setIncludedServices(Some(&NSArray::from_vec(includes)));
+            }
+            mutable_service
+        };
 
-            self.cb_peripheral_manager.addService(&mutable_service);
-
-            return self
+        if self.is_serving {
+            unsafe {
+                self.cb_peripheral_manager.addService(&mutable_service);
+            }
+            let added = self
                 .peripheral_delegate
                 .ensure_service_added(service.uuid)
                 .await;
+            if let Err(err) = added {
+                self.peripheral_delegate.remove_static_values(service.uuid);
+                return Err(err);
+            }
+        }
+
+        self.cached_services
+            .retain(|(uuid, _)| *uuid != service.uuid);
+        self.cached_services.push((service.uuid, mutable_service));
+        self.cached_characteristics.extend(cb_characteristics);
+        return Ok(());
+    }
+
+    // CoreBluetooth indicates Service Changed to connected centrals itself
+    fn remove_service(&mut self, service: Uuid) -> Result<(), Error> {
+        let Some(index) = self
+            .cached_services
+            .iter()
+            .position(|(uuid, _)| *uuid == service)
+        else {
+            return Err(Error::from_string(
+                format!("Service {service} not registered"),
+                ErrorType::ServiceNotFound,
+            ));
+        };
+        let (_, mutable_service) = self.cached_services.remove(index);
+        self.peripheral_delegate.remove_static_values(service);
+        self.cached_characteristics
+            .retain(|characteristic, _| characteristic.service != service);
+        unsafe {
+            if self.is_serving {
+                self.cb_peripheral_manager.removeService(&mutable_service);
+            }
         }
+        return Ok(());
     }
 }
 
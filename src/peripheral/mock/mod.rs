@@ -0,0 +1,554 @@
+mod simulated_central;
+
+pub use self::simulated_central::{Notification, SimulatedCentral};
+
+use super::PeripheralImpl;
+use crate::{
+    advertisement::{AdvertisementConfig, AdvertisementId},
+    error::{Error, ErrorType},
+    gatt::{
+        attribute::{service_attributes, Attribute, AttributeType},
+        characteristic::{Characteristic, CharacteristicId},
+        client_mtu::ClientMtus,
+        descriptor::Descriptor,
+        long_read::LongReads,
+        peripheral_event::{
+            AddressType, LinkType, NotificationResult, NotificationStatus, PeripheralEvent,
+            PeripheralRequest,
+        },
+        prepared_write::PreparedWrites,
+        properties::CharacteristicProperty,
+        response_timeout::ResponseTimeout,
+        service::Service,
+        value_store::ValueStore,
+    },
+};
+use async_trait::async_trait;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+use tokio::sync::mpsc::{Sender, UnboundedSender};
+use uuid::Uuid;
+
+// Legacy advertising sets are usually limited to a handful per controller
+const DEFAULT_ADVERTISING_INSTANCES: u8 = 4;
+
+/// In-memory peripheral without any Bluetooth stack, driven by [`SimulatedCentral`]s
+#[derive(Debug)]
+pub struct Peripheral {
+    state: Arc<Mutex<MockState>>,
+    sender_tx: Sender<PeripheralEvent>,
+    value_store: ValueStore,
+    long_reads: LongReads,
+    prepared_writes: PreparedWrites,
+    response_timeout: ResponseTimeout,
+}
+
+#[derive(Debug)]
+pub(crate) struct MockState {
+    pub powered: bool,
+    pub serving: bool,
+    pub advertisements: BTreeMap<AdvertisementId, AdvertisementConfig>,
+    pub last_advertisement: AdvertisementId,
+    pub advertising_instances: u8,
+    pub services: Vec<Service>,
+    pub centrals: HashMap<String, UnboundedSender<Notification>>,
+    pub subscriptions: HashMap<(Uuid, Uuid), BTreeSet<String>>,
+    pub client_mtus: ClientMtus,
+    pub value_store: ValueStore,
+    pub long_reads: LongReads,
+    pub prepared_writes: PreparedWrites,
+    pub response_timeout: ResponseTimeout,
+}
+
+impl MockState {
+    // Only served services can be discovered by centrals
+    pub fn find_characteristic(
+        &self,
+        service: Uuid,
+        characteristic: Uuid,
+    ) -> Option<&Characteristic> {
+        if !self.serving {
+            return None;
+        }
+        self.services
+            .iter()
+            .filter(|s| s.uuid == service)
+            .flat_map(|s| s.characteristics.iter())
+            .find(|c| c.uuid == characteristic)
+    }
+
+    pub fn find_descriptor(
+        &self,
+        service: Uuid,
+        characteristic: Uuid,
+        descriptor: Uuid,
+    ) -> Option<&Descriptor> {
+        self.find_characteristic(service, characteristic)?
+            .descriptors
+            .iter()
+            .find(|d| d.uuid == descriptor)
+    }
+}
+
+pub(crate) fn lock_state(state: &Mutex<MockState>) -> Result<MutexGuard<'_, MockState>, Error> {
+    state
+        .lock()
+        .map_err(|err| Error::from_string(err.to_string(), ErrorType::Mock))
+}
+
+// Drop the connection of a central together with its subscriptions and prepared writes,
+// returning whether it was connected
+pub(crate) async fn disconnect_central(
+    state: &Mutex<MockState>,
+    sender_tx: &Sender<PeripheralEvent>,
+    client: &str,
+) -> Result<bool, Error> {
+    let (connected, mtu, unsubscribed, prepared_writes) = {
+        let mut state = lock_state(state)?;
+        let connected = state.centrals.remove(client).is_some();
+        let mtu = state.client_mtus.get(client);
+        state.client_mtus.remove(client);
+        state.long_reads.remove_client(client);
+        let unsubscribed: Vec<(Uuid, Uuid)> = state
+            .subscriptions
+            .iter_mut()
+            .filter_map(|(key, clients)| clients.remove(client).then_some(*key))
+            .collect();
+        (connected, mtu, unsubscribed, state.prepared_writes.clone())
+    };
+    let request = |service: Uuid, characteristic: Uuid| PeripheralRequest {
+        client: client.to_string(),
+        service,
+        characteristic,
+        mtu,
+        link: Some(LinkType::Le),
+    };
+
+    for write in prepared_writes.take(client, Duration::ZERO) {
+        sender_tx
+            .send(PeripheralEvent::PreparedWriteCancelled {
+                request: request(write.service, write.characteristic),
+            })
+            .await?;
+    }
+
+    for (service, characteristic) in unsubscribed {
+        sender_tx
+            .send(PeripheralEvent::CharacteristicSubscriptionUpdate {
+                request: request(service, characteristic),
+                subscribed: false,
+            })
+            .await?;
+    }
+
+    if connected {
+        sender_tx
+            .send(PeripheralEvent::ClientDisconnected {
+                client: client.to_string(),
+                address_type: Some(AddressType::LePublic),
+            })
+            .await?;
+    }
+    Ok(connected)
+}
+
+#[async_trait]
+impl PeripheralImpl for Peripheral {
+    type Peripheral = Self;
+
+    async fn new(sender_tx: Sender<PeripheralEvent>) -> Result<Self, Error> {
+        let value_store = ValueStore::default();
+        let long_reads = LongReads::default();
+        let prepared_writes = PreparedWrites::default();
+        let response_timeout = ResponseTimeout::default();
+        Ok(Peripheral {
+            state: Arc::new(Mutex::new(MockState {
+                powered: true,
+                serving: false,
+                advertisements: BTreeMap::new(),
+                last_advertisement: AdvertisementId::PRIMARY,
+                advertising_instances: DEFAULT_ADVERTISING_INSTANCES,
+                services: Vec::new(),
+                centrals: HashMap::new(),
+                subscriptions: HashMap::new(),
+                client_mtus: ClientMtus::default(),
+                value_store: value_store.clone(),
+                long_reads: long_reads.clone(),
+                prepared_writes: prepared_writes.clone(),
+                response_timeout: response_timeout.clone(),
+            })),
+            sender_tx,
+            value_store,
+            long_reads,
+            prepared_writes,
+            response_timeout,
+        })
+    }
+
+    async fn is_powered(&mut self) -> Result<bool, Error> {
+        Ok(lock_state(&self.state)?.powered)
+    }
+
+    async fn is_advertising(&mut self) -> Result<bool, Error> {
+        Ok(!lock_state(&self.state)?.advertisements.is_empty())
+    }
+
+    async fn start_advertising(&mut self, name: &str, uuids: &[Uuid]) -> Result<(), Error> {
+        self.start_advertising_with(&AdvertisementConfig::new(name, uuids))
+            .await
+    }
+
+    async fn start_advertising_with(&mut self, config: &AdvertisementConfig) -> Result<(), Error> {
+        self.advertise(AdvertisementId::PRIMARY, config)
+    }
+
+    async fn stop_advertising(&mut self) -> Result<(), Error> {
+        lock_state(&self.state)?.advertisements.clear();
+        Ok(())
+    }
+
+    async fn supported_advertising_instances(&mut self) -> Result<u8, Error> {
+        Ok(lock_state(&self.state)?.advertising_instances)
+    }
+
+    async fn add_advertisement(
+        &mut self,
+        config: &AdvertisementConfig,
+    ) -> Result<AdvertisementId, Error> {
+        let advertisement = lock_state(&self.state)?.last_advertisement.next();
+        self.advertise(advertisement, config)?;
+        lock_state(&self.state)?.last_advertisement = advertisement;
+        Ok(advertisement)
+    }
+
+    async fn update_advertisement(
+        &mut self,
+        advertisement: AdvertisementId,
+        config: &AdvertisementConfig,
+    ) -> Result<(), Error> {
+        if !lock_state(&self.state)?
+            .advertisements
+            .contains_key(&advertisement)
+        {
+            return Err(Error::from_string(
+                format!("Advertisement {advertisement:?} not found"),
+                ErrorType::AdvertisementNotFound,
+            ));
+        }
+        self.advertise(advertisement, config)
+    }
+
+    async fn remove_advertisement(&mut self, advertisement: AdvertisementId) -> Result<(), Error> {
+        if lock_state(&self.state)?
+            .advertisements
+            .remove(&advertisement)
+            .is_none()
+        {
+            return Err(Error::from_string(
+                format!("Advertisement {advertisement:?} not found"),
+                ErrorType::AdvertisementNotFound,
+            ));
+        }
+        Ok(())
+    }
+
+    async fn is_serving(&mut self) -> Result<bool, Error> {
+        Ok(lock_state(&self.state)?.serving)
+    }
+
+    async fn start_serving(&mut self) -> Result<(), Error> {
+        lock_state(&self.state)?.serving = true;
+        Ok(())
+    }
+
+    async fn stop_serving(&mut self) -> Result<(), Error> {
+        lock_state(&self.state)?.serving = false;
+        self.end_subscriptions(None).await
+    }
+
+    async fn add_service(&mut self, service: &Service) -> Result<(), Error> {
+        let mut state = lock_state(&self.state)?;
+        service.validate(&state.services)?;
+        if let Some(include) = service
+            .includes
+            .iter()
+            .find(|include| !state.services.iter().any(|s| s.uuid == **include))
+        {
+            return Err(Error::from_string(
+                format!("Included service {include} not registered"),
+                ErrorType::ServiceNotFound,
+            ));
+        }
+        state.services.push(service.clone());
+        Ok(())
+    }
+
+    async fn remove_service(&mut self, service: Uuid) -> Result<(), Error> {
+        {
+            let mut state = lock_state(&self.state)?;
+            let count = state.services.len();
+            state.services.retain(|s| s.uuid != service);
+            if state.services.len() == count {
+                return Err(Error::from_string(
+                    format!("Service {service} not registered"),
+                    ErrorType::ServiceNotFound,
+                ));
+            }
+        }
+        self.end_subscriptions(Some(service)).await
+    }
+
+    async fn update_characteristic(
+        &mut self,
+        characteristic: CharacteristicId,
+        value: Vec<u8>,
+    ) -> Result<(), Error> {
+        self.notify(characteristic, value, None)?;
+        Ok(())
+    }
+
+    async fn update_characteristic_with_results(
+        &mut self,
+        characteristic: CharacteristicId,
+        value: Vec<u8>,
+        clients: Option<&[String]>,
+    ) -> Result<Vec<NotificationResult>, Error> {
+        self.notify(characteristic, value, clients)
+    }
+
+    // Handles are allocated in order like BlueZ does, with the declaration of a characteristic
+    // right before its value and fixed handles taking precedence
+    async fn attribute_table(&mut self) -> Result<Vec<Attribute>, Error> {
+        let state = lock_state(&self.state)?;
+        let mut next_handle: u16 = 1;
+        let mut attributes: Vec<Attribute> = Vec::new();
+        for mut attribute in state.services.iter().flat_map(service_attributes) {
+            if attribute.attribute_type == AttributeType::Characteristic {
+                next_handle = next_handle.saturating_add(1);
+            }
+            let handle = attribute.handle.unwrap_or(next_handle);
+            next_handle = handle.saturating_add(1);
+            attribute.handle = Some(handle);
+            attributes.push(attribute);
+        }
+        attributes.sort_by_key(|attribute| attribute.handle);
+        Ok(attributes)
+    }
+
+    async fn client_mtu(&mut self, client: &str) -> Result<Option<u16>, Error> {
+        Ok(lock_state(&self.state)?.client_mtus.get(client))
+    }
+
+    async fn connected_clients(&mut self) -> Result<Vec<String>, Error> {
+        let mut clients: Vec<String> = lock_state(&self.state)?.centrals.keys().cloned().collect();
+        clients.sort();
+        Ok(clients)
+    }
+
+    async fn disconnect_client(&mut self, client: &str) -> Result<(), Error> {
+        if !disconnect_central(&self.state, &self.sender_tx, client).await? {
+            return Err(Error::from_string(
+                format!("Client {client} not connected"),
+                ErrorType::ClientNotFound,
+            ));
+        }
+        Ok(())
+    }
+
+    fn value_store(&self) -> ValueStore {
+        self.value_store.clone()
+    }
+
+    fn long_reads(&self) -> LongReads {
+        self.long_reads.clone()
+    }
+
+    fn prepared_writes(&self) -> PreparedWrites {
+        self.prepared_writes.clone()
+    }
+
+    fn response_timeout(&self) -> ResponseTimeout {
+        self.response_timeout.clone()
+    }
+}
+
+impl Peripheral {
+    /// Create a central identified by `client`, which shows up as `PeripheralRequest::client`
+    pub fn simulated_central(&self, client: &str) -> SimulatedCentral {
+        SimulatedCentral::new(client, self.state.clone(), self.sender_tx.clone())
+    }
+
+    /// Configuration of the advertisement started with `start_advertising`
+    pub fn advertisement(&self) -> Result<Option<AdvertisementConfig>, Error> {
+        Ok(lock_state(&self.state)?
+            .advertisements
+            .get(&AdvertisementId::PRIMARY)
+            .cloned())
+    }
+
+    /// Configurations of all running advertisements
+    pub fn advertisements(&self) -> Result<BTreeMap<AdvertisementId, AdvertisementConfig>, Error> {
+        Ok(lock_state(&self.state)?.advertisements.clone())
+    }
+
+    /// Simulate an adapter supporting `instances` concurrent advertisements
+    pub fn set_advertising_instances(&mut self, instances: u8) -> Result<(), Error> {
+        lock_state(&self.state)?.advertising_instances = instances;
+        Ok(())
+    }
+
+    /// Simulate the adapter being switched on or off
+    pub async fn set_powered(&mut self, powered: bool) -> Result<(), Error> {
+        {
+            let mut state = lock_state(&self.state)?;
+            if state.powered == powered {
+                return Ok(());
+            }
+            state.powered = powered;
+            if !powered {
+                state.advertisements.clear();
+                state.serving = false;
+            }
+        }
+        self.sender_tx
+            .send(PeripheralEvent::StateUpdate {
+                is_powered: powered,
+            })
+            .await?;
+        if !powered {
+            self.end_subscriptions(None).await?;
+        }
+        Ok(())
+    }
+
+    // Rejects configs whose legacy payloads would not fit, as a real adapter does
+    fn advertise(
+        &self,
+        advertisement: AdvertisementId,
+        config: &AdvertisementConfig,
+    ) -> Result<(), Error> {
+        config.encode()?;
+        let mut state = lock_state(&self.state)?;
+        if !state.powered {
+            return Err(Error::from_string(
+                "Adapter is not powered".to_string(),
+                ErrorType::NotPowered,
+            ));
+        }
+        if !state.advertisements.contains_key(&advertisement)
+            && state.advertisements.len() >= state.advertising_instances as usize
+        {
+            return Err(Error::from_string(
+                "No advertising instance available".to_string(),
+                ErrorType::AdvertisingLimitReached,
+            ));
+        }
+        state.advertisements.insert(advertisement, config.clone());
+        if config.connectable {
+            state.serving = true;
+        }
+        Ok(())
+    }
+
+    // Drop the subscriptions to the characteristics of the service, or of all services
+    async fn end_subscriptions(&self, service: Option<Uuid>) -> Result<(), Error> {
+        let unsubscribed: Vec<PeripheralRequest> = {
+            let mut state = lock_state(&self.state)?;
+            let client_mtus = state.client_mtus.clone();
+            let keys: Vec<(Uuid, Uuid)> = state
+                .subscriptions
+                .keys()
+                .filter(|(s, _)| service.is_none_or(|service| *s == service))
+                .cloned()
+                .collect();
+            keys.into_iter()
+                .filter_map(|key| {
+                    state
+                        .subscriptions
+                        .remove(&key)
+                        .map(|clients| (key, clients))
+                })
+                .flat_map(|((service, characteristic), clients)| {
+                    let client_mtus = client_mtus.clone();
+                    clients.into_iter().map(move |client| PeripheralRequest {
+                        mtu: client_mtus.get(&client),
+                        link: Some(LinkType::Le),
+                        client,
+                        service,
+                        characteristic,
+                    })
+                })
+                .collect()
+        };
+
+        for request in unsubscribed {
+            self.sender_tx
+                .send(PeripheralEvent::CharacteristicSubscriptionUpdate {
+                    request,
+                    subscribed: false,
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    // Simulated centrals confirm indications as soon as they receive them
+    fn notify(
+        &self,
+        characteristic: CharacteristicId,
+        value: Vec<u8>,
+        clients: Option<&[String]>,
+    ) -> Result<Vec<NotificationResult>, Error> {
+        let state = lock_state(&self.state)?;
+        state.value_store.update(characteristic, &value);
+        let mut results: Vec<NotificationResult> = Vec::new();
+        for ((service, char), subscribers) in state.subscriptions.iter() {
+            if *service != characteristic.service || *char != characteristic.characteristic {
+                continue;
+            }
+            let indicate_only = state.find_characteristic(*service, *char).is_some_and(|c| {
+                !c.properties.iter().any(|p| {
+                    *p == CharacteristicProperty::Notify
+                        || *p == CharacteristicProperty::NotifyEncryptionRequired
+                })
+            });
+            for client in subscribers {
+                if clients.is_some_and(|clients| !clients.contains(client)) {
+                    continue;
+                }
+                let delivered = state.centrals.get(client).is_some_and(|central| {
+                    central
+                        .send(Notification {
+                            service: *service,
+                            characteristic: *char,
+                            value: value.clone(),
+                        })
+                        .is_ok()
+                });
+                let status = match (delivered, indicate_only) {
+                    (false, _) => NotificationStatus::ClientGone,
+                    (true, true) => NotificationStatus::Confirmed,
+                    (true, false) => NotificationStatus::Sent,
+                };
+                results.push(NotificationResult {
+                    client: client.clone(),
+                    status,
+                });
+            }
+        }
+
+        // Requested clients which are not subscribed at all
+        for client in clients.unwrap_or_default() {
+            if !results.iter().any(|result| result.client == *client) {
+                results.push(NotificationResult {
+                    client: client.clone(),
+                    status: NotificationStatus::ClientGone,
+                });
+            }
+        }
+        Ok(results)
+    }
+}
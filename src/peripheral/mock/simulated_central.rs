@@ -0,0 +1,645 @@
+use super::{disconnect_central, lock_state, MockState};
+use crate::{
+    error::{Error, ErrorType},
+    gatt::{
+        characteristic::CharacteristicId,
+        client_mtu::DEFAULT_MTU,
+        peripheral_event::{
+            AddressType, DescriptorRequest, LinkType, PeripheralEvent, PeripheralRequest,
+            ReadRequestResponse, RequestResponse, WriteRequestResponse,
+        },
+        prepared_write::PreparedWrites,
+        properties::CharacteristicProperty,
+        response_timeout::ResponseTimeout,
+    },
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{
+    mpsc::{self, Sender, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+use uuid::Uuid;
+
+// Largest MTU, a value of the maximum 512 bytes fits into a single PDU with it
+const MAX_MTU: u16 = 517;
+
+/// Value pushed to a subscribed central through `update_characteristic`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub service: Uuid,
+    pub characteristic: Uuid,
+    pub value: Vec<u8>,
+}
+
+/// Fake central talking to a mock [`Peripheral`](super::Peripheral)
+///
+/// Requests issued through it are delivered as `PeripheralEvent`s on the peripheral's channel,
+/// and the responses sent back on the responders are returned to the caller.
+/// Failed requests resolve to the ATT error a real central would receive.
+#[derive(Debug)]
+pub struct SimulatedCentral {
+    client: String,
+    state: Arc<Mutex<MockState>>,
+    sender_tx: Sender<PeripheralEvent>,
+    notification_tx: UnboundedSender<Notification>,
+    notification_rx: UnboundedReceiver<Notification>,
+    mtu: u16,
+    // Prepared writes queued while the crate does not reassemble them
+    queued_writes: Vec<QueuedWrite>,
+}
+
+#[derive(Debug)]
+struct QueuedWrite {
+    service: Uuid,
+    characteristic: Uuid,
+    value: Vec<u8>,
+    offset: u64,
+}
+
+impl SimulatedCentral {
+    pub(crate) fn new(
+        client: &str,
+        state: Arc<Mutex<MockState>>,
+        sender_tx: Sender<PeripheralEvent>,
+    ) -> Self {
+        let (notification_tx, notification_rx) = mpsc::unbounded_channel();
+        SimulatedCentral {
+            client: client.to_string(),
+            state,
+            sender_tx,
+            notification_tx,
+            notification_rx,
+            mtu: DEFAULT_MTU,
+            queued_writes: Vec::new(),
+        }
+    }
+
+    pub fn client(&self) -> &str {
+        &self.client
+    }
+
+    pub fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    /// Exchange the ATT MTU, which is 23 until then and at most 517
+    pub fn exchange_mtu(&mut self, mtu: u16) -> Result<u16, Error> {
+        self.mtu = mtu.clamp(DEFAULT_MTU, MAX_MTU);
+        let state = lock_state(&self.state)?;
+        if state.centrals.contains_key(&self.client) {
+            state.client_mtus.update(&self.client, Some(self.mtu));
+        }
+        Ok(self.mtu)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        lock_state(&self.state)
+            .map(|state| state.centrals.contains_key(&self.client))
+            .unwrap_or(false)
+    }
+
+    /// Uuids of the served primary services, as found by service discovery
+    pub fn primary_services(&self) -> Vec<Uuid> {
+        lock_state(&self.state)
+            .ok()
+            .filter(|state| state.serving)
+            .map(|state| {
+                state
+                    .services
+                    .iter()
+                    .filter(|s| s.primary)
+                    .map(|s| s.uuid)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Uuids of the services included by `service`, the only way to find secondary services
+    pub fn included_services(&self, service: Uuid) -> Vec<Uuid> {
+        lock_state(&self.state)
+            .ok()
+            .filter(|state| state.serving)
+            .and_then(|state| {
+                state
+                    .services
+                    .iter()
+                    .find(|s| s.uuid == service)
+                    .map(|s| s.includes.clone())
+            })
+            .unwrap_or_default()
+    }
+
+    /// Connect to the peripheral, which must be running a connectable advertisement.
+    /// Reported as `ClientConnected` unless already connected.
+    pub async fn connect(&mut self) -> Result<(), Error> {
+        let already_connected = {
+            let mut state = lock_state(&self.state)?;
+            if !state
+                .advertisements
+                .values()
+                .any(|config| config.connectable)
+            {
+                return Err(Error::from_string(
+                    "Peripheral is not connectable".to_string(),
+                    ErrorType::Mock,
+                ));
+            }
+            state.client_mtus.update(&self.client, Some(self.mtu));
+            state
+                .centrals
+                .insert(self.client.clone(), self.notification_tx.clone())
+                .is_some()
+        };
+
+        if !already_connected {
+            // Writes queued before the peripheral dropped the connection are lost with it
+            self.queued_writes.clear();
+            self.sender_tx
+                .send(PeripheralEvent::ClientConnected {
+                    client: self.client.clone(),
+                    address_type: Some(AddressType::LePublic),
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Disconnect from the peripheral, dropping all subscriptions and prepared writes.
+    /// Reported as `ClientDisconnected` unless not connected.
+    pub async fn disconnect(&mut self) -> Result<(), Error> {
+        self.queued_writes.clear();
+        disconnect_central(&self.state, &self.sender_tx, &self.client).await?;
+        Ok(())
+    }
+
+    /// Read a characteristic, getting at most MTU - 1 bytes like from a real ATT server.
+    /// Longer values are read on at increasing offsets.
+    pub async fn read(
+        &self,
+        service: Uuid,
+        characteristic: Uuid,
+        offset: u64,
+    ) -> Result<Vec<u8>, RequestResponse> {
+        let value = self.read_value(service, characteristic, offset).await?;
+        Ok(self.fit_read_response(value))
+    }
+
+    async fn read_value(
+        &self,
+        service: Uuid,
+        characteristic: Uuid,
+        offset: u64,
+    ) -> Result<Vec<u8>, RequestResponse> {
+        self.check_property(service, characteristic, &[CharacteristicProperty::Read])?;
+        if let Some(value) = self.static_value(service, characteristic)? {
+            return read_static_value(&value, offset);
+        }
+        let characteristic_id = CharacteristicId::new(service, characteristic);
+        if let Some(result) = self.stored_value(characteristic_id, offset)? {
+            return result;
+        }
+        let long_reads = lock_state(&self.state)
+            .map_err(|_| RequestResponse::UnlikelyError)?
+            .long_reads
+            .clone();
+        if let Some(result) =
+            long_reads.read(&self.client, characteristic_id, offset, Some(self.mtu))
+        {
+            return result;
+        }
+
+        let (res_tx, res_rx) = oneshot::channel::<ReadRequestResponse>();
+        self.sender_tx
+            .send(PeripheralEvent::ReadRequest {
+                request: self.request(service, characteristic),
+                offset: long_reads.request_offset(offset),
+                responder: res_tx,
+            })
+            .await
+            .map_err(|_| RequestResponse::UnlikelyError)?;
+
+        let res = self
+            .response_timeout()?
+            .wait(
+                &self.sender_tx,
+                self.request(service, characteristic),
+                None,
+                res_rx,
+            )
+            .await?;
+        match res.response {
+            RequestResponse::Success => long_reads.respond(
+                &self.client,
+                characteristic_id,
+                offset,
+                Some(self.mtu),
+                res.value,
+            ),
+            response => Err(response),
+        }
+    }
+
+    pub async fn write(
+        &self,
+        service: Uuid,
+        characteristic: Uuid,
+        value: Vec<u8>,
+        offset: u64,
+    ) -> Result<(), RequestResponse> {
+        self.check_property(
+            service,
+            characteristic,
+            &[
+                CharacteristicProperty::Write,
+                CharacteristicProperty::WriteWithoutResponse,
+                CharacteristicProperty::AuthenticatedSignedWrites,
+            ],
+        )?;
+
+        self.send_write(service, characteristic, value, offset)
+            .await
+    }
+
+    /// Queue a fragment of a long or reliable write, delivered on `execute_write`
+    pub async fn prepare_write(
+        &mut self,
+        service: Uuid,
+        characteristic: Uuid,
+        value: Vec<u8>,
+        offset: u64,
+    ) -> Result<(), RequestResponse> {
+        self.check_property(service, characteristic, &[CharacteristicProperty::Write])?;
+        let prepared_writes = self.prepared_writes()?;
+        if prepared_writes.is_enabled() {
+            return prepared_writes.prepare(&self.client, service, characteristic, offset, &value);
+        }
+        self.queued_writes.push(QueuedWrite {
+            service,
+            characteristic,
+            value,
+            offset,
+        });
+        Ok(())
+    }
+
+    /// Write the prepared values, stopping at the first write the application rejects
+    pub async fn execute_write(&mut self) -> Result<(), RequestResponse> {
+        let mut writes: Vec<QueuedWrite> = std::mem::take(&mut self.queued_writes);
+        writes.extend(
+            self.prepared_writes()?
+                .take(&self.client, Duration::ZERO)
+                .into_iter()
+                .map(|write| QueuedWrite {
+                    service: write.service,
+                    characteristic: write.characteristic,
+                    value: write.value,
+                    offset: 0,
+                }),
+        );
+
+        for write in writes {
+            self.send_write(
+                write.service,
+                write.characteristic,
+                write.value,
+                write.offset,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn cancel_write(&mut self) -> Result<(), RequestResponse> {
+        self.queued_writes.clear();
+        for write in self.prepared_writes()?.take(&self.client, Duration::ZERO) {
+            self.sender_tx
+                .send(PeripheralEvent::PreparedWriteCancelled {
+                    request: self.request(write.service, write.characteristic),
+                })
+                .await
+                .map_err(|_| RequestResponse::UnlikelyError)?;
+        }
+        Ok(())
+    }
+
+    /// Read a descriptor, answered from its static value if it has one and cut to MTU - 1 bytes
+    pub async fn read_descriptor(
+        &self,
+        service: Uuid,
+        characteristic: Uuid,
+        descriptor: Uuid,
+        offset: u64,
+    ) -> Result<Vec<u8>, RequestResponse> {
+        let value = self.check_descriptor_property(
+            service,
+            characteristic,
+            descriptor,
+            &[CharacteristicProperty::Read],
+        )?;
+        if let Some(value) = value {
+            return read_static_value(&value, offset).map(|value| self.fit_read_response(value));
+        }
+
+        let (res_tx, res_rx) = oneshot::channel::<ReadRequestResponse>();
+        self.sender_tx
+            .send(PeripheralEvent::DescriptorReadRequest {
+                request: self.descriptor_request(service, characteristic, descriptor),
+                offset,
+                responder: res_tx,
+            })
+            .await
+            .map_err(|_| RequestResponse::UnlikelyError)?;
+
+        let res = self
+            .response_timeout()?
+            .wait(
+                &self.sender_tx,
+                self.request(service, characteristic),
+                Some(descriptor),
+                res_rx,
+            )
+            .await?;
+        match res.response {
+            RequestResponse::Success => Ok(self.fit_read_response(res.value)),
+            response => Err(response),
+        }
+    }
+
+    pub async fn write_descriptor(
+        &self,
+        service: Uuid,
+        characteristic: Uuid,
+        descriptor: Uuid,
+        value: Vec<u8>,
+        offset: u64,
+    ) -> Result<(), RequestResponse> {
+        self.check_descriptor_property(
+            service,
+            characteristic,
+            descriptor,
+            &[
+                CharacteristicProperty::Write,
+                CharacteristicProperty::WriteWithoutResponse,
+                CharacteristicProperty::AuthenticatedSignedWrites,
+            ],
+        )?;
+
+        let (res_tx, res_rx) = oneshot::channel::<WriteRequestResponse>();
+        self.sender_tx
+            .send(PeripheralEvent::DescriptorWriteRequest {
+                request: self.descriptor_request(service, characteristic, descriptor),
+                value,
+                offset,
+                responder: res_tx,
+            })
+            .await
+            .map_err(|_| RequestResponse::UnlikelyError)?;
+
+        let res = self
+            .response_timeout()?
+            .wait(
+                &self.sender_tx,
+                self.request(service, characteristic),
+                Some(descriptor),
+                res_rx,
+            )
+            .await?;
+        match res.response {
+            RequestResponse::Success => Ok(()),
+            response => Err(response),
+        }
+    }
+
+    pub async fn subscribe(
+        &mut self,
+        service: Uuid,
+        characteristic: Uuid,
+    ) -> Result<(), RequestResponse> {
+        self.set_subscription(service, characteristic, true).await
+    }
+
+    pub async fn unsubscribe(
+        &mut self,
+        service: Uuid,
+        characteristic: Uuid,
+    ) -> Result<(), RequestResponse> {
+        self.set_subscription(service, characteristic, false).await
+    }
+
+    /// Wait for the next notification or indication sent to this central
+    pub async fn notification(&mut self) -> Option<Notification> {
+        self.notification_rx.recv().await
+    }
+
+    pub fn try_notification(&mut self) -> Option<Notification> {
+        self.notification_rx.try_recv().ok()
+    }
+
+    async fn set_subscription(
+        &mut self,
+        service: Uuid,
+        characteristic: Uuid,
+        subscribed: bool,
+    ) -> Result<(), RequestResponse> {
+        self.check_property(
+            service,
+            characteristic,
+            &[
+                CharacteristicProperty::Notify,
+                CharacteristicProperty::NotifyEncryptionRequired,
+                CharacteristicProperty::Indicate,
+                CharacteristicProperty::IndicateEncryptionRequired,
+            ],
+        )?;
+
+        let changed = {
+            let mut state = lock_state(&self.state).map_err(|_| RequestResponse::UnlikelyError)?;
+            let clients = state
+                .subscriptions
+                .entry((service, characteristic))
+                .or_default();
+            if subscribed {
+                clients.insert(self.client.clone())
+            } else {
+                clients.remove(&self.client)
+            }
+        };
+
+        if changed {
+            self.sender_tx
+                .send(PeripheralEvent::CharacteristicSubscriptionUpdate {
+                    request: self.request(service, characteristic),
+                    subscribed,
+                })
+                .await
+                .map_err(|_| RequestResponse::UnlikelyError)?;
+        }
+        Ok(())
+    }
+
+    // Resolve the attribute the way the ATT server would before the request reaches the app
+    fn check_property(
+        &self,
+        service: Uuid,
+        characteristic: Uuid,
+        properties: &[CharacteristicProperty],
+    ) -> Result<(), RequestResponse> {
+        let state = lock_state(&self.state).map_err(|_| RequestResponse::UnlikelyError)?;
+        if !state.centrals.contains_key(&self.client) {
+            return Err(RequestResponse::UnlikelyError);
+        }
+        let char = state
+            .find_characteristic(service, characteristic)
+            .ok_or(RequestResponse::InvalidHandle)?;
+        if !char.properties.iter().any(|p| properties.contains(p)) {
+            return Err(missing_property_response(properties));
+        }
+        Ok(())
+    }
+
+    fn static_value(
+        &self,
+        service: Uuid,
+        characteristic: Uuid,
+    ) -> Result<Option<Vec<u8>>, RequestResponse> {
+        let state = lock_state(&self.state).map_err(|_| RequestResponse::UnlikelyError)?;
+        Ok(state
+            .find_characteristic(service, characteristic)
+            .and_then(|char| char.value.clone()))
+    }
+
+    fn prepared_writes(&self) -> Result<PreparedWrites, RequestResponse> {
+        let state = lock_state(&self.state).map_err(|_| RequestResponse::UnlikelyError)?;
+        Ok(state.prepared_writes.clone())
+    }
+
+    fn response_timeout(&self) -> Result<ResponseTimeout, RequestResponse> {
+        let state = lock_state(&self.state).map_err(|_| RequestResponse::UnlikelyError)?;
+        Ok(state.response_timeout.clone())
+    }
+
+    // Deliver a write the ATT server accepted to the application
+    async fn send_write(
+        &self,
+        service: Uuid,
+        characteristic: Uuid,
+        value: Vec<u8>,
+        offset: u64,
+    ) -> Result<(), RequestResponse> {
+        let (res_tx, res_rx) = oneshot::channel::<WriteRequestResponse>();
+        self.sender_tx
+            .send(PeripheralEvent::WriteRequest {
+                request: self.request(service, characteristic),
+                value: value.clone(),
+                offset,
+                responder: res_tx,
+            })
+            .await
+            .map_err(|_| RequestResponse::UnlikelyError)?;
+
+        let res = self
+            .response_timeout()?
+            .wait(
+                &self.sender_tx,
+                self.request(service, characteristic),
+                None,
+                res_rx,
+            )
+            .await?;
+        match res.response {
+            RequestResponse::Success => {
+                let state = lock_state(&self.state).map_err(|_| RequestResponse::UnlikelyError)?;
+                state.value_store.write(
+                    CharacteristicId::new(service, characteristic),
+                    &value,
+                    offset,
+                )
+            }
+            response => Err(response),
+        }
+    }
+
+    fn stored_value(
+        &self,
+        characteristic: CharacteristicId,
+        offset: u64,
+    ) -> Result<Option<Result<Vec<u8>, RequestResponse>>, RequestResponse> {
+        let state = lock_state(&self.state).map_err(|_| RequestResponse::UnlikelyError)?;
+        Ok(state.value_store.read(characteristic, offset))
+    }
+
+    // Same as check_property, returning the static value of the descriptor
+    fn check_descriptor_property(
+        &self,
+        service: Uuid,
+        characteristic: Uuid,
+        descriptor: Uuid,
+        properties: &[CharacteristicProperty],
+    ) -> Result<Option<Vec<u8>>, RequestResponse> {
+        let state = lock_state(&self.state).map_err(|_| RequestResponse::UnlikelyError)?;
+        if !state.centrals.contains_key(&self.client) {
+            return Err(RequestResponse::UnlikelyError);
+        }
+        let descriptor = state
+            .find_descriptor(service, characteristic, descriptor)
+            .ok_or(RequestResponse::InvalidHandle)?;
+        if !descriptor.properties.iter().any(|p| properties.contains(p)) {
+            return Err(missing_property_response(properties));
+        }
+        Ok(descriptor.value.clone())
+    }
+
+    fn descriptor_request(
+        &self,
+        service: Uuid,
+        characteristic: Uuid,
+        descriptor: Uuid,
+    ) -> DescriptorRequest {
+        DescriptorRequest {
+            client: self.client.clone(),
+            service,
+            characteristic,
+            descriptor,
+            mtu: Some(self.mtu),
+            link: Some(LinkType::Le),
+        }
+    }
+
+    // A read response carries the opcode and at most MTU - 1 bytes of the value
+    fn fit_read_response(&self, mut value: Vec<u8>) -> Vec<u8> {
+        value.truncate(usize::from(self.mtu) - 1);
+        value
+    }
+
+    fn request(&self, service: Uuid, characteristic: Uuid) -> PeripheralRequest {
+        PeripheralRequest {
+            client: self.client.clone(),
+            service,
+            characteristic,
+            mtu: Some(self.mtu),
+            link: Some(LinkType::Le),
+        }
+    }
+}
+
+fn read_static_value(value: &[u8], offset: u64) -> Result<Vec<u8>, RequestResponse> {
+    value
+        .get(offset as usize..)
+        .map(|value| value.to_vec())
+        .ok_or(RequestResponse::InvalidOffset)
+}
+
+// Reads and writes of attributes without the property are not permitted, other requests are
+// not supported
+fn missing_property_response(properties: &[CharacteristicProperty]) -> RequestResponse {
+    if properties.contains(&CharacteristicProperty::Read) {
+        RequestResponse::ReadNotPermitted
+    } else if properties.contains(&CharacteristicProperty::Write) {
+        RequestResponse::WriteNotPermitted
+    } else {
+        RequestResponse::RequestNotSupported
+    }
+}
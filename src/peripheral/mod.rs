@@ -1,27 +1,42 @@
-#[cfg(any(target_os = "macos", target_os = "ios"))]
+#[cfg(all(any(target_os = "macos", target_os = "ios"), not(feature = "mock")))]
 mod corebluetooth;
-#[cfg(any(target_os = "macos", target_os = "ios"))]
+#[cfg(all(any(target_os = "macos", target_os = "ios"), not(feature = "mock")))]
 pub use self::corebluetooth::Peripheral;
 
-#[cfg(any(target_os = "linux", target_os = "android"))]
+#[cfg(all(any(target_os = "linux", target_os = "android"), not(feature = "mock")))]
 mod bluez;
-#[cfg(any(target_os = "linux", target_os = "android"))]
+#[cfg(all(any(target_os = "linux", target_os = "android"), not(feature = "mock")))]
 pub use self::bluez::Peripheral;
 
-#[cfg(target_os = "windows")]
+#[cfg(all(target_os = "windows", not(feature = "mock")))]
 mod winrt;
-#[cfg(target_os = "windows")]
+#[cfg(all(target_os = "windows", not(feature = "mock")))]
 pub use self::winrt::Peripheral;
 
+#[cfg(feature = "mock")]
+pub mod mock;
+#[cfg(feature = "mock")]
+pub use self::mock::Peripheral;
+
 use crate::{
+    advertisement::{AdvertisementConfig, AdvertisementId},
     error::Error,
-    gatt::{peripheral_event::PeripheralEvent, service::Service},
+    gatt::{
+        attribute::Attribute,
+        characteristic::CharacteristicId,
+        long_read::LongReads,
+        peripheral_event::{NotificationResult, PeripheralEvent},
+        prepared_write::PreparedWrites,
+        response_timeout::ResponseTimeout,
+        service::Service,
+        value_store::ValueStore,
+    },
 };
 use async_trait::async_trait;
 use tokio::sync::mpsc::Sender;
 use uuid::Uuid;
 
-#[cfg(any(target_os = "linux", target_os = "android"))]
+#[cfg(all(any(target_os = "linux", target_os = "android"), not(feature = "mock")))]
 #[async_trait]
 pub trait PeripheralImpl: Send + Sync {
     type Peripheral: PeripheralImpl + Send + Sync;
@@ -29,7 +44,9 @@ pub trait PeripheralImpl: Send + Sync {
     async fn new(
         sender_tx: Sender<PeripheralEvent>,
         agent: Option<bluer::agent::Agent>,
-    ) -> Result<Peripheral, Error>;
+    ) -> Result<Self, Error>
+    where
+        Self: Sized;
 
     async fn is_powered(&mut self) -> Result<bool, Error>;
 
@@ -37,23 +54,82 @@ pub trait PeripheralImpl: Send + Sync {
 
     async fn start_advertising(&mut self, name: &str, uuids: &[Uuid]) -> Result<(), Error>;
 
+    /// Advertise with `config`. A connectable advertisement also starts serving the services,
+    /// which stay served after advertising stops until `stop_serving`
+    async fn start_advertising_with(&mut self, config: &AdvertisementConfig) -> Result<(), Error>;
+
     async fn stop_advertising(&mut self) -> Result<(), Error>;
 
+    async fn supported_advertising_instances(&mut self) -> Result<u8, Error>;
+
+    async fn add_advertisement(
+        &mut self,
+        config: &AdvertisementConfig,
+    ) -> Result<AdvertisementId, Error>;
+
+    async fn update_advertisement(
+        &mut self,
+        advertisement: AdvertisementId,
+        config: &AdvertisementConfig,
+    ) -> Result<(), Error>;
+
+    async fn remove_advertisement(&mut self, advertisement: AdvertisementId) -> Result<(), Error>;
+
+    async fn is_serving(&mut self) -> Result<bool, Error>;
+
+    async fn start_serving(&mut self) -> Result<(), Error>;
+
+    /// Stop serving the services. Windows only advertises served services, so this stops
+    /// advertising there as well
+    async fn stop_serving(&mut self) -> Result<(), Error>;
+
     async fn add_service(&mut self, service: &Service) -> Result<(), Error>;
 
+    async fn remove_service(&mut self, service: Uuid) -> Result<(), Error>;
+
     async fn update_characteristic(
         &mut self,
-        characteristic: Uuid,
+        characteristic: CharacteristicId,
         value: Vec<u8>,
     ) -> Result<(), Error>;
+
+    async fn update_characteristic_with_results(
+        &mut self,
+        characteristic: CharacteristicId,
+        value: Vec<u8>,
+        clients: Option<&[String]>,
+    ) -> Result<Vec<NotificationResult>, Error>;
+
+    /// Attributes of the added services with their handles, sorted by handle once all are known
+    async fn attribute_table(&mut self) -> Result<Vec<Attribute>, Error>;
+
+    /// ATT MTU of the client as last reported with its requests or subscriptions,
+    /// `None` before the first one
+    async fn client_mtu(&mut self, client: &str) -> Result<Option<u16>, Error>;
+
+    /// Clients connected to the adapter, identified like the `client` of their requests
+    async fn connected_clients(&mut self) -> Result<Vec<String>, Error>;
+
+    /// Drop the connection of a client, failing with `ClientNotFound` if it is not connected
+    async fn disconnect_client(&mut self, client: &str) -> Result<(), Error>;
+
+    fn value_store(&self) -> ValueStore;
+
+    fn long_reads(&self) -> LongReads;
+
+    fn prepared_writes(&self) -> PreparedWrites;
+
+    fn response_timeout(&self) -> ResponseTimeout;
 }
 
-#[cfg(not(any(target_os = "linux", target_os = "android")))]
+#[cfg(any(not(any(target_os = "linux", target_os = "android")), feature = "mock"))]
 #[async_trait]
 pub trait PeripheralImpl: Send + Sync {
     type Peripheral: PeripheralImpl + Send + Sync;
 
-    async fn new(sender_tx: Sender<PeripheralEvent>) -> Result<Peripheral, Error>;
+    async fn new(sender_tx: Sender<PeripheralEvent>) -> Result<Self, Error>
+    where
+        Self: Sized;
 
     async fn is_powered(&mut self) -> Result<bool, Error>;
 
@@ -61,13 +137,70 @@ pub trait PeripheralImpl: Send + Sync {
 
     async fn start_advertising(&mut self, name: &str, uuids: &[Uuid]) -> Result<(), Error>;
 
+    /// Advertise with `config`. A connectable advertisement also starts serving the services,
+    /// which stay served after advertising stops until `stop_serving`
+    async fn start_advertising_with(&mut self, config: &AdvertisementConfig) -> Result<(), Error>;
+
     async fn stop_advertising(&mut self) -> Result<(), Error>;
 
+    async fn supported_advertising_instances(&mut self) -> Result<u8, Error>;
+
+    async fn add_advertisement(
+        &mut self,
+        config: &AdvertisementConfig,
+    ) -> Result<AdvertisementId, Error>;
+
+    async fn update_advertisement(
+        &mut self,
+        advertisement: AdvertisementId,
+        config: &AdvertisementConfig,
+    ) -> Result<(), Error>;
+
+    async fn remove_advertisement(&mut self, advertisement: AdvertisementId) -> Result<(), Error>;
+
+    async fn is_serving(&mut self) -> Result<bool, Error>;
+
+    async fn start_serving(&mut self) -> Result<(), Error>;
+
+    /// Stop serving the services. Windows only advertises served services, so this stops
+    /// advertising there as well
+    async fn stop_serving(&mut self) -> Result<(), Error>;
+
     async fn add_service(&mut self, service: &Service) -> Result<(), Error>;
 
+    async fn remove_service(&mut self, service: Uuid) -> Result<(), Error>;
+
     async fn update_characteristic(
         &mut self,
-        characteristic: Uuid,
+        characteristic: CharacteristicId,
         value: Vec<u8>,
     ) -> Result<(), Error>;
+
+    async fn update_characteristic_with_results(
+        &mut self,
+        characteristic: CharacteristicId,
+        value: Vec<u8>,
+        clients: Option<&[String]>,
+    ) -> Result<Vec<NotificationResult>, Error>;
+
+    /// Attributes of the added services with their handles, sorted by handle once all are known
+    async fn attribute_table(&mut self) -> Result<Vec<Attribute>, Error>;
+
+    /// ATT MTU of the client as last reported with its requests or subscriptions,
+    /// `None` before the first one
+    async fn client_mtu(&mut self, client: &str) -> Result<Option<u16>, Error>;
+
+    /// Clients connected to the adapter, identified like the `client` of their requests
+    async fn connected_clients(&mut self) -> Result<Vec<String>, Error>;
+
+    /// Drop the connection of a client, failing with `ClientNotFound` if it is not connected
+    async fn disconnect_client(&mut self, client: &str) -> Result<(), Error>;
+
+    fn value_store(&self) -> ValueStore;
+
+    fn long_reads(&self) -> LongReads;
+
+    fn prepared_writes(&self) -> PreparedWrites;
+
+    fn response_timeout(&self) -> ResponseTimeout;
 }
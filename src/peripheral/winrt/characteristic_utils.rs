@@ -10,12 +10,22 @@ use crate::gatt::{
 pub fn get_gatt_characteristic_properties(
     characteristic: &Characteristic,
 ) -> windows::core::Result<GattCharacteristicProperties> {
-    return Ok(characteristic
+    let mut properties = characteristic
         .properties
         .iter()
         .fold(GattCharacteristicProperties::None, |acc, property| {
             acc | property.clone().to_gatt_property()
-        }));
+        });
+    // Windows generates the extended properties descriptor from these flags
+    if let Some(extended_properties) = characteristic.extended_properties() {
+        if extended_properties.reliable_write {
+            properties |= GattCharacteristicProperties::ReliableWrites;
+        }
+        if extended_properties.writable_auxiliaries {
+            properties |= GattCharacteristicProperties::WritableAuxiliaries;
+        }
+    }
+    return Ok(properties);
 }
 
 pub fn get_protection_level(
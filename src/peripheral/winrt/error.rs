@@ -1,14 +1,27 @@
 use futures::channel::mpsc::SendError;
+use windows::core::HRESULT;
 
 use crate::error::{Error, ErrorType};
 
+const E_ACCESSDENIED: HRESULT = HRESULT(0x80070005_u32 as i32);
+// HRESULTs of ERROR_NOT_READY and ERROR_DEVICE_NOT_AVAILABLE
+const E_NOT_READY: HRESULT = HRESULT(0x80070015_u32 as i32);
+const E_DEVICE_NOT_AVAILABLE: HRESULT = HRESULT(0x800710DF_u32 as i32);
+
 impl From<windows::core::Error> for Error {
     fn from(value: windows::core::Error) -> Self {
-        Error::new(
-            format!("windows::core::Error: {:?}", value.code()),
-            format!("{:?}", value),
-            ErrorType::Windows,
-        )
+        let code = value.code();
+        let error_type = if code == E_ACCESSDENIED {
+            ErrorType::PermissionDenied
+        } else if code == E_NOT_READY {
+            ErrorType::NotPowered
+        // Null results, e.g. the default adapter when there is none, are errors without a code
+        } else if code.is_ok() || code == E_DEVICE_NOT_AVAILABLE {
+            ErrorType::AdapterNotFound
+        } else {
+            ErrorType::Windows
+        };
+        Error::from_source(value, error_type)
     }
 }
 
@@ -6,15 +6,32 @@ mod win_utils;
 
 use self::peripheral_manager::PeripheralManager;
 use super::PeripheralImpl;
-use crate::error::Error;
-use crate::gatt::peripheral_event::PeripheralEvent;
+use crate::advertisement::{AdvertisementConfig, AdvertisementId};
+use crate::error::{Error, ErrorType};
+use crate::gatt::attribute::{service_attributes, Attribute};
+use crate::gatt::characteristic::CharacteristicId;
+use crate::gatt::client_mtu::ClientMtus;
+use crate::gatt::long_read::LongReads;
+use crate::gatt::peripheral_event::{NotificationResult, PeripheralEvent};
+use crate::gatt::prepared_write::PreparedWrites;
+use crate::gatt::response_timeout::ResponseTimeout;
 use crate::gatt::service::Service;
+use crate::gatt::value_store::ValueStore;
 use async_trait::async_trait;
 use tokio::sync::mpsc::Sender;
 use uuid::Uuid;
 
 pub struct Peripheral {
     peripheral_manager: PeripheralManager,
+    // Windows advertises the published services as a single advertisement
+    advertisement: Option<AdvertisementId>,
+    last_advertisement: AdvertisementId,
+    services: Vec<Service>,
+    value_store: ValueStore,
+    long_reads: LongReads,
+    prepared_writes: PreparedWrites,
+    response_timeout: ResponseTimeout,
+    client_mtus: ClientMtus,
 }
 
 #[async_trait]
@@ -22,8 +39,29 @@ impl PeripheralImpl for Peripheral {
     type Peripheral = Self;
 
     async fn new(sender_tx: Sender<PeripheralEvent>) -> Result<Self, Error> {
+        let value_store = ValueStore::default();
+        let long_reads = LongReads::default();
+        let prepared_writes = PreparedWrites::default();
+        let response_timeout = ResponseTimeout::default();
+        let client_mtus = ClientMtus::default();
         Ok(Self {
-            peripheral_manager: PeripheralManager::new(sender_tx).await,
+            peripheral_manager: PeripheralManager::new(
+                sender_tx,
+                value_store.clone(),
+                long_reads.clone(),
+                prepared_writes.clone(),
+                response_timeout.clone(),
+                client_mtus.clone(),
+            )
+            .await,
+            advertisement: None,
+            last_advertisement: AdvertisementId::PRIMARY,
+            services: Vec::new(),
+            value_store,
+            long_reads,
+            prepared_writes,
+            response_timeout,
+            client_mtus,
         })
     }
 
@@ -32,16 +70,78 @@ impl PeripheralImpl for Peripheral {
     }
 
     async fn start_advertising(&mut self, name: &str, uuids: &[Uuid]) -> Result<(), Error> {
-        if let Err(err) = self.peripheral_manager.start_advertising(name, uuids).await {
+        return self
+            .start_advertising_with(&AdvertisementConfig::new(name, uuids))
+            .await;
+    }
+
+    async fn start_advertising_with(&mut self, config: &AdvertisementConfig) -> Result<(), Error> {
+        return self.advertise(AdvertisementId::PRIMARY, config).await;
+    }
+
+    async fn stop_advertising(&mut self) -> Result<(), Error> {
+        if let Err(err) = self.peripheral_manager.stop_advertising().await {
             return Err(Error::from(err));
         }
+        self.advertisement = None;
         Ok(())
     }
 
-    async fn stop_advertising(&mut self) -> Result<(), Error> {
-        if let Err(err) = self.peripheral_manager.stop_advertising().await {
+    async fn supported_advertising_instances(&mut self) -> Result<u8, Error> {
+        Ok(1)
+    }
+
+    async fn add_advertisement(
+        &mut self,
+        config: &AdvertisementConfig,
+    ) -> Result<AdvertisementId, Error> {
+        let advertisement = self.last_advertisement.next();
+        self.advertise(advertisement, config).await?;
+        self.last_advertisement = advertisement;
+        Ok(advertisement)
+    }
+
+    async fn update_advertisement(
+        &mut self,
+        advertisement: AdvertisementId,
+        config: &AdvertisementConfig,
+    ) -> Result<(), Error> {
+        if self.advertisement != Some(advertisement) {
+            return Err(Error::from_string(
+                format!("Advertisement {advertisement:?} not found"),
+                ErrorType::AdvertisementNotFound,
+            ));
+        }
+        self.advertise(advertisement, config).await
+    }
+
+    async fn remove_advertisement(&mut self, advertisement: AdvertisementId) -> Result<(), Error> {
+        if self.advertisement != Some(advertisement) {
+            return Err(Error::from_string(
+                format!("Advertisement {advertisement:?} not found"),
+                ErrorType::AdvertisementNotFound,
+            ));
+        }
+        self.stop_advertising().await
+    }
+
+    async fn is_serving(&mut self) -> Result<bool, Error> {
+        Ok(self.peripheral_manager.is_serving()?)
+    }
+
+    async fn start_serving(&mut self) -> Result<(), Error> {
+        if let Err(err) = self.peripheral_manager.start_serving() {
+            return Err(Error::from(err));
+        }
+        Ok(())
+    }
+
+    async fn stop_serving(&mut self) -> Result<(), Error> {
+        if let Err(err) = self.peripheral_manager.stop_serving() {
             return Err(Error::from(err));
         }
+        // Advertising stops along with the service providers
+        self.advertisement = None;
         Ok(())
     }
 
@@ -50,24 +150,132 @@ impl PeripheralImpl for Peripheral {
     }
 
     async fn add_service(&mut self, service: &Service) -> Result<(), Error> {
+        service.validate(&self.services)?;
+        if service_attributes(service)
+            .iter()
+            .any(|attribute| attribute.handle.is_some())
+        {
+            log::warn!("Windows assigns all handles itself, fixed handles are ignored");
+        }
+        if !service.includes.is_empty() {
+            return Err(Error::from_string(
+                "Windows does not support included services".to_string(),
+                ErrorType::NotSupported,
+            ));
+        }
         if let Err(err) = self.peripheral_manager.add_service(service).await {
             return Err(Error::from(err));
         }
+        self.services.push(service.clone());
+        Ok(())
+    }
+
+    async fn remove_service(&mut self, service: Uuid) -> Result<(), Error> {
+        if !self.services.iter().any(|s| s.uuid == service) {
+            return Err(Error::from_string(
+                format!("Service {service} not registered"),
+                ErrorType::ServiceNotFound,
+            ));
+        }
+        if let Err(err) = self.peripheral_manager.remove_service(service).await {
+            return Err(Error::from(err));
+        }
+        self.services.retain(|s| s.uuid != service);
         Ok(())
     }
 
     async fn update_characteristic(
         &mut self,
-        characteristic: Uuid,
+        characteristic: CharacteristicId,
         value: Vec<u8>,
     ) -> Result<(), Error> {
-        if let Err(err) = self
-            .peripheral_manager
-            .update_characteristic(characteristic, value)
+        self.value_store.update(characteristic, &value);
+        self.peripheral_manager
+            .update_characteristic(characteristic, value, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn update_characteristic_with_results(
+        &mut self,
+        characteristic: CharacteristicId,
+        value: Vec<u8>,
+        clients: Option<&[String]>,
+    ) -> Result<Vec<NotificationResult>, Error> {
+        self.value_store.update(characteristic, &value);
+        self.peripheral_manager
+            .update_characteristic(characteristic, value, clients)
             .await
+    }
+
+    // Windows does not report the handles of local attributes
+    async fn attribute_table(&mut self) -> Result<Vec<Attribute>, Error> {
+        Ok(self
+            .services
+            .iter()
+            .flat_map(service_attributes)
+            .map(|attribute| Attribute {
+                handle: None,
+                ..attribute
+            })
+            .collect())
+    }
+
+    async fn client_mtu(&mut self, client: &str) -> Result<Option<u16>, Error> {
+        Ok(self.client_mtus.get(client))
+    }
+
+    // Windows does not expose the connections of the GATT server
+    async fn connected_clients(&mut self) -> Result<Vec<String>, Error> {
+        Err(Error::from_string(
+            "Connected clients are not reported by Windows".to_string(),
+            ErrorType::NotSupported,
+        ))
+    }
+
+    async fn disconnect_client(&mut self, client: &str) -> Result<(), Error> {
+        Err(Error::from_string(
+            format!("Client {client} cannot be disconnected on Windows"),
+            ErrorType::NotSupported,
+        ))
+    }
+
+    fn value_store(&self) -> ValueStore {
+        self.value_store.clone()
+    }
+
+    fn long_reads(&self) -> LongReads {
+        self.long_reads.clone()
+    }
+
+    fn prepared_writes(&self) -> PreparedWrites {
+        self.prepared_writes.clone()
+    }
+
+    fn response_timeout(&self) -> ResponseTimeout {
+        self.response_timeout.clone()
+    }
+}
+
+impl Peripheral {
+    async fn advertise(
+        &mut self,
+        advertisement: AdvertisementId,
+        config: &AdvertisementConfig,
+    ) -> Result<(), Error> {
+        if self
+            .advertisement
+            .is_some_and(|current| current != advertisement)
         {
+            return Err(Error::from_string(
+                "Windows supports a single advertisement".to_string(),
+                ErrorType::AdvertisingLimitReached,
+            ));
+        }
+        if let Err(err) = self.peripheral_manager.start_advertising(config).await {
             return Err(Error::from(err));
         }
+        self.advertisement = Some(advertisement);
         Ok(())
     }
 }
@@ -1,18 +1,32 @@
 use super::characteristic_utils::{get_gatt_characteristic_properties, get_protection_level};
 use super::win_event_handler::WinEventHandler;
 use super::win_utils::{
-    to_guid, vec_to_buffer, GattCharacteristicObject, GattServiceProviderObject,
+    device_id_from_session, to_guid, vec_to_buffer, GattCharacteristicObject,
+    GattServiceProviderObject,
 };
-use crate::gatt::peripheral_event::PeripheralEvent;
+use crate::advertisement::AdvertisementConfig;
+use crate::error::ErrorType;
+use crate::gatt::characteristic::CharacteristicId;
+use crate::gatt::client_mtu::ClientMtus;
+use crate::gatt::descriptor::{
+    PresentationFormat, EXTENDED_PROPERTIES_UUID, PRESENTATION_FORMAT_UUID, USER_DESCRIPTION_UUID,
+};
+use crate::gatt::long_read::LongReads;
+use crate::gatt::peripheral_event::{NotificationResult, NotificationStatus, PeripheralEvent};
+use crate::gatt::prepared_write::PreparedWrites;
+use crate::gatt::properties::CharacteristicProperty;
+use crate::gatt::response_timeout::ResponseTimeout;
 use crate::gatt::service::Service;
+use crate::gatt::value_store::ValueStore;
 use std::collections::HashMap;
 use tokio::sync::mpsc::Sender;
 use uuid::Uuid;
-use windows::core::{Error, HRESULT};
+use windows::core::{Error, HRESULT, HSTRING};
 use windows::Devices::Bluetooth::GenericAttributeProfile::{
-    GattLocalCharacteristicParameters, GattLocalDescriptorParameters, GattServiceProvider,
-    GattServiceProviderAdvertisementStatus, GattServiceProviderAdvertisingParameters,
-    GattSubscribedClient,
+    GattCharacteristicProperties, GattClientNotificationResult, GattCommunicationStatus,
+    GattLocalCharacteristicParameters, GattLocalDescriptorParameters, GattPresentationFormat,
+    GattServiceProvider, GattServiceProviderAdvertisementStatus,
+    GattServiceProviderAdvertisingParameters, GattSubscribedClient,
 };
 use windows::Devices::Bluetooth::{BluetoothAdapter, BluetoothError};
 use windows::Devices::Radios::{Radio, RadioKind};
@@ -21,13 +35,34 @@ use windows::Foundation::EventRegistrationToken;
 pub(crate) struct PeripheralManager {
     event_handler: WinEventHandler,
     services: HashMap<Uuid, GattServiceProviderObject>,
+    // Service providers are published while serving or advertising, discoverable only when advertising
+    is_serving: bool,
+    is_advertising: bool,
+    advertisement: AdvertisementConfig,
 }
 
 impl PeripheralManager {
-    pub(crate) async fn new(sender_tx: Sender<PeripheralEvent>) -> Self {
+    pub(crate) async fn new(
+        sender_tx: Sender<PeripheralEvent>,
+        value_store: ValueStore,
+        long_reads: LongReads,
+        prepared_writes: PreparedWrites,
+        response_timeout: ResponseTimeout,
+        client_mtus: ClientMtus,
+    ) -> Self {
         let manager = Self {
-            event_handler: WinEventHandler::new(sender_tx.clone()),
+            event_handler: WinEventHandler::new(
+                sender_tx.clone(),
+                value_store,
+                long_reads,
+                prepared_writes,
+                response_timeout,
+                client_mtus,
+            ),
             services: HashMap::new(),
+            is_serving: false,
+            is_advertising: false,
+            advertisement: AdvertisementConfig::default(),
         };
         if let Err(err) = manager.set_radio_listener().await {
             log::error!("Error setting radio listener: {}", err);
@@ -52,40 +87,79 @@ impl PeripheralManager {
     }
 
     pub(crate) async fn is_advertising(&self) -> windows::core::Result<bool> {
-        if self.services.is_empty() {
+        if self.services.is_empty() || !self.is_advertising {
             return Ok(false);
         }
         return Ok(self.are_all_services_started()?);
     }
 
-    pub(crate) async fn start_advertising(&self, _: &str, _: &[Uuid]) -> windows::core::Result<()> {
-        let advertisement_parameter = GattServiceProviderAdvertisingParameters::new()?;
-        advertisement_parameter.SetIsDiscoverable(true)?;
-        advertisement_parameter.SetIsConnectable(true)?;
-
+    // Windows advertises the published services on its own, only their service data can be set
+    pub(crate) async fn start_advertising(
+        &mut self,
+        config: &AdvertisementConfig,
+    ) -> windows::core::Result<()> {
         // TODO: add name and uuid in advertisement or change adapter name
-        for gatt_object in self.services.values().into_iter() {
-            if gatt_object.obj.AdvertisementStatus()?
-                == GattServiceProviderAdvertisementStatus::Started
-            {
-                log::debug!("Already advertising");
-                continue;
-            }
-            gatt_object
-                .obj
-                .StartAdvertisingWithParameters(&advertisement_parameter)?;
+        if !config.manufacturer_data.is_empty()
+            || !config.solicit_uuids.is_empty()
+            || config.appearance.is_some()
+            || config.tx_power.is_some()
+            || config.discoverable_timeout.is_some()
+            || config.min_interval.is_some()
+            || config.max_interval.is_some()
+            || !config.includes.is_empty()
+        {
+            log::warn!("Windows only advertises the service data of registered services");
         }
+        self.advertisement = config.clone();
+        self.is_advertising = true;
+        // Clients connecting through the advertisement expect the services to be there
+        if config.connectable {
+            self.is_serving = true;
+        }
+        self.publish_services()
+    }
 
-        Ok(())
+    pub(crate) async fn stop_advertising(&mut self) -> windows::core::Result<()> {
+        self.is_advertising = false;
+        self.publish_services()
     }
 
-    pub(crate) async fn stop_advertising(&self) -> windows::core::Result<()> {
-        for gatt_object in self.services.values().into_iter() {
+    pub(crate) fn is_serving(&self) -> windows::core::Result<bool> {
+        if !self.is_serving {
+            return Ok(false);
+        }
+        return Ok(self.are_all_services_started()?);
+    }
+
+    pub(crate) fn start_serving(&mut self) -> windows::core::Result<()> {
+        self.is_serving = true;
+        self.publish_services()
+    }
+
+    // Windows only advertises published services, so this stops advertising as well
+    pub(crate) fn stop_serving(&mut self) -> windows::core::Result<()> {
+        self.is_serving = false;
+        self.is_advertising = false;
+        self.publish_services()
+    }
+
+    // Restart the service providers with the current discoverability, or stop them
+    fn publish_services(&self) -> windows::core::Result<()> {
+        for (uuid, gatt_object) in self.services.iter() {
             if gatt_object.obj.AdvertisementStatus()?
                 != GattServiceProviderAdvertisementStatus::Stopped
             {
                 gatt_object.obj.StopAdvertising()?;
             }
+            if self.is_serving || self.is_advertising {
+                gatt_object
+                    .obj
+                    .StartAdvertisingWithParameters(&get_advertising_parameters(
+                        self.is_advertising && self.advertisement.discoverable,
+                        !self.is_advertising || self.advertisement.connectable,
+                        self.advertisement.service_data.get(uuid),
+                    )?)?;
+            }
         }
         Ok(())
     }
@@ -115,8 +189,38 @@ impl PeripheralManager {
             parameters.SetCharacteristicProperties(properties)?;
             parameters.SetWriteProtectionLevel(write_protection_level)?;
             parameters.SetReadProtectionLevel(read_protection_level)?;
-            if let Some(value) = &characteristic.value {
-                parameters.SetStaticValue(&vec_to_buffer(value.clone()))?;
+            for descriptor in &characteristic.descriptors {
+                let Some(value) = &descriptor.value else {
+                    continue;
+                };
+                if descriptor.uuid == USER_DESCRIPTION_UUID {
+                    parameters.SetUserDescription(&HSTRING::from(
+                        String::from_utf8_lossy(value).as_ref(),
+                    ))?;
+                } else if descriptor.uuid == PRESENTATION_FORMAT_UUID {
+                    if let Some(format) = PresentationFormat::from_bytes(value) {
+                        parameters.PresentationFormats()?.Append(
+                            &GattPresentationFormat::FromParts(
+                                format.format,
+                                format.exponent.into(),
+                                format.unit,
+                                format.namespace,
+                                format.description,
+                            )?,
+                        )?;
+                    }
+                }
+            }
+            // Windows only serves static values of read-only characteristics, the read handler the rest
+            let is_read_only = characteristic.properties.iter().all(|property| {
+                *property == CharacteristicProperty::Read
+                    || *property == CharacteristicProperty::Broadcast
+            });
+            let mut static_value = characteristic.value.clone();
+            if is_read_only {
+                if let Some(value) = static_value.take() {
+                    parameters.SetStaticValue(&vec_to_buffer(value))?;
+                }
             }
 
             // Add characteristic to Service provider
@@ -133,6 +237,13 @@ impl PeripheralManager {
 
             // Add descriptor
             for descriptor in &characteristic.descriptors {
+                // Created by Windows from the characteristic parameters
+                if descriptor.uuid == EXTENDED_PROPERTIES_UUID
+                    || descriptor.uuid == USER_DESCRIPTION_UUID
+                    || descriptor.uuid == PRESENTATION_FORMAT_UUID
+                {
+                    continue;
+                }
                 let descriptoruuid = to_guid(&descriptor.uuid);
                 let parameters: GattLocalDescriptorParameters =
                     GattLocalDescriptorParameters::new()?;
@@ -153,11 +264,38 @@ impl PeripheralManager {
                     return Err(Error::new(HRESULT(1), "Error creating a descriptor"));
                 }
 
-                descriptor_result.Descriptor()?;
+                // Static values are served by Windows, other reads go to the application
+                let win_descriptor = descriptor_result.Descriptor()?;
+                if descriptor.value.is_none()
+                    && descriptor
+                        .properties
+                        .contains(&CharacteristicProperty::Read)
+                {
+                    win_descriptor.ReadRequested(
+                        &self
+                            .event_handler
+                            .create_descriptor_read_handler(service.uuid, characteristic.uuid),
+                    )?;
+                }
+                if descriptor.properties.iter().any(|p| {
+                    *p == CharacteristicProperty::Write
+                        || *p == CharacteristicProperty::WriteWithoutResponse
+                        || *p == CharacteristicProperty::AuthenticatedSignedWrites
+                }) {
+                    win_descriptor.WriteRequested(
+                        &self
+                            .event_handler
+                            .create_descriptor_write_handler(service.uuid, characteristic.uuid),
+                    )?;
+                }
             }
 
             let read_token: Result<EventRegistrationToken, Error> = win_characteristic
-                .ReadRequested(&self.event_handler.create_read_handler(service.uuid));
+                .ReadRequested(
+                    &self
+                        .event_handler
+                        .create_read_handler(service.uuid, static_value),
+                );
             let write_token: Result<EventRegistrationToken, Error> = win_characteristic
                 .WriteRequested(&self.event_handler.create_write_handler(service.uuid));
             let subscribed_clients_token = win_characteristic.SubscribedClientsChanged(
@@ -193,22 +331,91 @@ impl PeripheralManager {
             self.services
                 .insert(service.uuid, gatt_service_provider_object);
         }
+
+        // Services added while serving or advertising are published right away
+        if self.is_serving || self.is_advertising {
+            service_provider.StartAdvertisingWithParameters(&get_advertising_parameters(
+                self.is_advertising && self.advertisement.discoverable,
+                !self.is_advertising || self.advertisement.connectable,
+                self.advertisement.service_data.get(&service.uuid),
+            )?)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn remove_service(&mut self, service: Uuid) -> windows::core::Result<()> {
+        let Some(gatt_object) = self.services.get(&service) else {
+            return Err(Error::new(HRESULT(1), "Service not registered"));
+        };
+        // Only forget the provider once it stopped, so a failed removal can be retried
+        if gatt_object.obj.AdvertisementStatus()? != GattServiceProviderAdvertisementStatus::Stopped
+        {
+            gatt_object.obj.StopAdvertising()?;
+        }
+        self.services.remove(&service);
         Ok(())
     }
 
     pub async fn update_characteristic(
         &mut self,
-        characteristic: Uuid,
+        characteristic: CharacteristicId,
         value: Vec<u8>,
-    ) -> Result<(), Error> {
-        let char = self
+        clients: Option<&[String]>,
+    ) -> Result<Vec<NotificationResult>, crate::error::Error> {
+        let Some(char) = self
             .services
-            .values()
-            .find_map(|service| service.characteristics.get(&characteristic).map(|c| &c.obj))
-            .expect("Characteristic not found");
-        let notify_async = char.NotifyValueAsync(&vec_to_buffer(value))?;
-        notify_async.await?;
-        return Ok(());
+            .get(&characteristic.service)
+            .and_then(|service| service.characteristics.get(&characteristic.characteristic))
+            .map(|c| &c.obj)
+        else {
+            return Err(crate::error::Error::from_string(
+                format!("Characteristic {characteristic:?} not registered"),
+                ErrorType::CharacteristicNotFound,
+            ));
+        };
+
+        // Notify operations complete once indications are confirmed
+        let properties = char.CharacteristicProperties()?;
+        let indicate_only = properties.contains(GattCharacteristicProperties::Indicate)
+            && !properties.contains(GattCharacteristicProperties::Notify);
+
+        let mut results: Vec<NotificationResult> = Vec::new();
+        match clients {
+            None => {
+                let notify_async = char.NotifyValueAsync(&vec_to_buffer(value))?;
+                for result in notify_async.await? {
+                    results.push(to_notification_result(&result, indicate_only)?);
+                }
+            }
+            Some(clients) => {
+                let targets: Vec<GattSubscribedClient> = char
+                    .SubscribedClients()?
+                    .into_iter()
+                    .filter(|subscribed_client| {
+                        subscribed_client
+                            .Session()
+                            .is_ok_and(|session| clients.contains(&device_id_from_session(session)))
+                    })
+                    .collect();
+                for subscribed_client in targets {
+                    let notify_async = char.NotifyValueForSubscribedClientAsync(
+                        &vec_to_buffer(value.clone()),
+                        &subscribed_client,
+                    )?;
+                    let result = notify_async.await?;
+                    results.push(to_notification_result(&result, indicate_only)?);
+                }
+                for client in clients {
+                    if !results.iter().any(|result| result.client == *client) {
+                        results.push(NotificationResult {
+                            client: client.clone(),
+                            status: NotificationStatus::ClientGone,
+                        });
+                    }
+                }
+            }
+        }
+        return Ok(results);
     }
 
     fn are_all_services_started(&self) -> windows::core::Result<bool> {
@@ -221,3 +428,37 @@ impl PeripheralManager {
         return Ok(true);
     }
 }
+
+fn to_notification_result(
+    result: &GattClientNotificationResult,
+    indicate_only: bool,
+) -> windows::core::Result<NotificationResult> {
+    let status = match result.Status()? {
+        GattCommunicationStatus::Success if indicate_only => NotificationStatus::Confirmed,
+        GattCommunicationStatus::Success => NotificationStatus::Sent,
+        GattCommunicationStatus::Unreachable => NotificationStatus::ClientGone,
+        GattCommunicationStatus::ProtocolError => NotificationStatus::Failed(format!(
+            "Protocol error: {:?}",
+            result.ProtocolError().and_then(|error| error.Value()).ok()
+        )),
+        status => NotificationStatus::Failed(format!("{:?}", status)),
+    };
+    return Ok(NotificationResult {
+        client: device_id_from_session(result.SubscribedClient()?.Session()?),
+        status,
+    });
+}
+
+fn get_advertising_parameters(
+    is_discoverable: bool,
+    is_connectable: bool,
+    service_data: Option<&Vec<u8>>,
+) -> windows::core::Result<GattServiceProviderAdvertisingParameters> {
+    let advertisement_parameter = GattServiceProviderAdvertisingParameters::new()?;
+    advertisement_parameter.SetIsDiscoverable(is_discoverable)?;
+    advertisement_parameter.SetIsConnectable(is_connectable)?;
+    if let Some(service_data) = service_data {
+        advertisement_parameter.SetServiceData(&vec_to_buffer(service_data.clone()))?;
+    }
+    return Ok(advertisement_parameter);
+}
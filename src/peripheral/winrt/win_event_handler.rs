@@ -1,9 +1,16 @@
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 
+use crate::gatt::characteristic::CharacteristicId;
+use crate::gatt::client_mtu::ClientMtus;
+use crate::gatt::long_read::LongReads;
 use crate::gatt::peripheral_event::{
-    PeripheralEvent, PeripheralRequest, ReadRequestResponse, RequestResponse, WriteRequestResponse,
+    DescriptorRequest, LinkType, PeripheralEvent, PeripheralRequest, ReadRequestResponse,
+    RequestResponse, WriteRequestResponse,
 };
+use crate::gatt::prepared_write::PreparedWrites;
+use crate::gatt::response_timeout::ResponseTimeout;
+use crate::gatt::value_store::ValueStore;
 use crate::peripheral::winrt::win_utils::{
     buffer_to_vec, device_id_from_session, to_uuid, vec_to_buffer,
 };
@@ -12,14 +19,16 @@ use tokio::sync::oneshot;
 use uuid::Uuid;
 use windows::core::IInspectable;
 use windows::Devices::Bluetooth::GenericAttributeProfile::{
-    GattProtocolError, GattServiceProviderAdvertisementStatus, GattSubscribedClient,
+    GattProtocolError, GattServiceProviderAdvertisementStatus, GattSession, GattSessionStatus,
+    GattSessionStatusChangedEventArgs, GattSubscribedClient,
 };
 use windows::Devices::Radios::{Radio, RadioState};
 use windows::Foundation::Collections::IVectorView;
 use windows::{
     Devices::Bluetooth::GenericAttributeProfile::{
-        GattLocalCharacteristic, GattReadRequestedEventArgs, GattServiceProvider,
-        GattServiceProviderAdvertisementStatusChangedEventArgs, GattWriteRequestedEventArgs,
+        GattLocalCharacteristic, GattLocalDescriptor, GattReadRequest, GattReadRequestedEventArgs,
+        GattServiceProvider, GattServiceProviderAdvertisementStatusChangedEventArgs,
+        GattWriteRequestedEventArgs,
     },
     Foundation::TypedEventHandler,
 };
@@ -27,13 +36,36 @@ use windows::{
 pub struct WinEventHandler {
     sender_tx: Sender<PeripheralEvent>,
     connected_clients: Arc<RwLock<HashMap<(Uuid, Uuid), Vec<String>>>>,
+    value_store: ValueStore,
+    long_reads: LongReads,
+    prepared_writes: PreparedWrites,
+    response_timeout: ResponseTimeout,
+    client_mtus: ClientMtus,
+    sessions: Sessions,
 }
 
 impl WinEventHandler {
-    pub fn new(sender_tx: Sender<PeripheralEvent>) -> Self {
+    pub fn new(
+        sender_tx: Sender<PeripheralEvent>,
+        value_store: ValueStore,
+        long_reads: LongReads,
+        prepared_writes: PreparedWrites,
+        response_timeout: ResponseTimeout,
+        client_mtus: ClientMtus,
+    ) -> Self {
         Self {
             sender_tx,
             connected_clients: Arc::new(RwLock::new(HashMap::new())),
+            value_store,
+            long_reads: long_reads.clone(),
+            prepared_writes,
+            response_timeout,
+            client_mtus: client_mtus.clone(),
+            sessions: Sessions {
+                sessions: Arc::new(Mutex::new(HashMap::new())),
+                client_mtus,
+                long_reads,
+            },
         }
     }
 
@@ -80,6 +112,8 @@ impl WinEventHandler {
     ) -> TypedEventHandler<GattLocalCharacteristic, IInspectable> {
         let connected_clients = Arc::clone(&self.connected_clients);
         let sender_tx: Sender<PeripheralEvent> = self.sender_tx.clone();
+        let client_mtus = self.client_mtus.clone();
+        let sessions = self.sessions.clone();
 
         TypedEventHandler::new(
             move |originator: &Option<GattLocalCharacteristic>, _: &Option<IInspectable>| {
@@ -91,7 +125,7 @@ impl WinEventHandler {
                     
                 let new_clients: Vec<String> = subscribed_clients
                     .into_iter()
-                    .map(|client| device_id_from_session(client.Session().unwrap()))
+                    .map(|client| sessions.client(&client.Session().unwrap()))
                     .collect();
 
                 let mut old_clients_store = connected_clients.write().unwrap();
@@ -125,6 +159,8 @@ impl WinEventHandler {
                         if let Err(err) = sender_tx
                             .send(PeripheralEvent::CharacteristicSubscriptionUpdate {
                                 request: PeripheralRequest {
+                                    mtu: client_mtus.get(&client),
+                                    link: Some(LinkType::Le),
                                     client,
                                     service: service_uuid,
                                     characteristic: characteristic_uuid,
@@ -141,6 +177,8 @@ impl WinEventHandler {
                         if let Err(err) = sender_tx
                             .send(PeripheralEvent::CharacteristicSubscriptionUpdate {
                                 request: PeripheralRequest {
+                                    mtu: client_mtus.get(&client),
+                                    link: Some(LinkType::Le),
                                     client,
                                     service: service_uuid,
                                     characteristic: characteristic_uuid,
@@ -161,8 +199,14 @@ impl WinEventHandler {
     pub fn create_read_handler(
         &mut self,
         service_uuid: Uuid,
+        static_value: Option<Vec<u8>>,
     ) -> TypedEventHandler<GattLocalCharacteristic, GattReadRequestedEventArgs> {
         let sender_tx: Sender<PeripheralEvent> = self.sender_tx.clone();
+        let value_store = self.value_store.clone();
+        let long_reads = self.long_reads.clone();
+        let response_timeout = self.response_timeout.clone();
+        let client_mtus = self.client_mtus.clone();
+        let sessions = self.sessions.clone();
 
         TypedEventHandler::new(
             move |originator: &Option<GattLocalCharacteristic>,
@@ -173,16 +217,47 @@ impl WinEventHandler {
                 futures::executor::block_on(async {
                     let request = event_args.GetRequestAsync().unwrap().await;
                     if let Ok(request) = request {
-                        // let mtu = event_args.Session().unwrap().MaxPduSize().unwrap();
+                        if let Some(value) = &static_value {
+                            let offset = request.Offset().unwrap() as usize;
+                            if let Some(value) = value.get(offset..) {
+                                request
+                                    .RespondWithValue(&vec_to_buffer(value.to_vec()))
+                                    .unwrap();
+                                return;
+                            }
+                            request
+                                .RespondWithProtocolError(
+                                    GattProtocolError::InvalidOffset().unwrap(),
+                                )
+                                .unwrap();
+                            return;
+                        }
+
+                        let char_uuid = to_uuid(&characteristic.Uuid().unwrap());
+                        let characteristic_id = CharacteristicId::new(service_uuid, char_uuid);
+                        let client = sessions.client(&event_args.Session().unwrap());
+                        let offset = request.Offset().unwrap() as u64;
+                        let mtu = client_mtus.get(&client);
+                        if let Some(result) = value_store
+                            .read(characteristic_id, offset)
+                            .or_else(|| long_reads.read(&client, characteristic_id, offset, mtu))
+                        {
+                            respond_to_read(&request, result);
+                            return;
+                        }
+
+                        let peripheral_request = PeripheralRequest {
+                            mtu,
+                            link: Some(LinkType::Le),
+                            client: client.clone(),
+                            service: service_uuid,
+                            characteristic: char_uuid,
+                        };
                         let (resp_tx, resp_rx) = oneshot::channel::<ReadRequestResponse>();
                         if let Err(e) = sender_tx
                             .send(PeripheralEvent::ReadRequest {
-                                request: PeripheralRequest {
-                                    client: device_id_from_session(event_args.Session().unwrap()),
-                                    service: service_uuid,
-                                    characteristic: to_uuid(&characteristic.Uuid().unwrap()),
-                                },
-                                offset: request.Offset().unwrap() as u64,
+                                request: peripheral_request.clone(),
+                                offset: long_reads.request_offset(offset),
                                 responder: resp_tx,
                             })
                             .await
@@ -191,22 +266,16 @@ impl WinEventHandler {
                             return;
                         }
 
-                        if let Ok(result) = resp_rx.await {
-                            if result.response == RequestResponse::Success {
-                                request
-                                    .RespondWithValue(&vec_to_buffer(result.value))
-                                    .unwrap();
-                                return;
-                            }
-                            request
-                                .RespondWithProtocolError(result.response.to_gatt_protocol_error())
-                                .unwrap();
-                            return;
-                        }
-
-                        request
-                            .RespondWithProtocolError(GattProtocolError::UnlikelyError().unwrap())
-                            .unwrap();
+                        let result = match response_timeout
+                            .wait(&sender_tx, peripheral_request, None, resp_rx)
+                            .await
+                        {
+                            Ok(result) if result.response == RequestResponse::Success => long_reads
+                                .respond(&client, characteristic_id, offset, mtu, result.value),
+                            Ok(result) => Err(result.response),
+                            Err(response) => Err(response),
+                        };
+                        respond_to_read(&request, result);
                     }
                 });
 
@@ -220,6 +289,11 @@ impl WinEventHandler {
         service_uuid: Uuid,
     ) -> TypedEventHandler<GattLocalCharacteristic, GattWriteRequestedEventArgs> {
         let sender_tx = self.sender_tx.clone();
+        let value_store = self.value_store.clone();
+        let prepared_writes = self.prepared_writes.clone();
+        let response_timeout = self.response_timeout.clone();
+        let client_mtus = self.client_mtus.clone();
+        let sessions = self.sessions.clone();
 
         TypedEventHandler::new(
             move |originator: &Option<GattLocalCharacteristic>,
@@ -228,18 +302,107 @@ impl WinEventHandler {
                 let characteristic = originator.as_ref().unwrap();
                 futures::executor::block_on(async {
                     if let Ok(request) = event_args.GetRequestAsync().unwrap().await {
-                        // let offset = request.Offset().unwrap();
-                        // let mtu = event_args.Session().unwrap().MaxPduSize().unwrap();
+                        let client = sessions.client(&event_args.Session().unwrap());
                         let (resp_tx, resp_rx) = oneshot::channel::<WriteRequestResponse>();
                         let char_uuid = to_uuid(&characteristic.Uuid().unwrap());
+                        let value = buffer_to_vec(&request.Value().unwrap());
+                        let offset = request.Offset().unwrap() as u64;
+                        // Windows executes prepared writes itself, only their length is checked
+                        if prepared_writes.is_enabled()
+                            && offset as usize + value.len() > prepared_writes.max_length()
+                        {
+                            request
+                                .RespondWithProtocolError(
+                                    RequestResponse::InvalidAttributeValueLength
+                                        .to_gatt_protocol_error(),
+                                )
+                                .unwrap();
+                            return;
+                        }
+                        let peripheral_request = PeripheralRequest {
+                            mtu: client_mtus.get(&client),
+                            link: Some(LinkType::Le),
+                            client,
+                            service: service_uuid,
+                            characteristic: char_uuid,
+                        };
                         if let Err(e) = sender_tx
                             .send(PeripheralEvent::WriteRequest {
-                                request: PeripheralRequest {
-                                    client: device_id_from_session(event_args.Session().unwrap()),
+                                request: peripheral_request.clone(),
+                                value: value.clone(),
+                                offset,
+                                responder: resp_tx,
+                            })
+                            .await
+                        {
+                            log::error!("Error sending delegate event: {}", e);
+                            return;
+                        }
+
+                        let response = match response_timeout
+                            .wait(&sender_tx, peripheral_request, None, resp_rx)
+                            .await
+                        {
+                            Ok(result) if result.response == RequestResponse::Success => {
+                                match value_store.write(
+                                    CharacteristicId::new(service_uuid, char_uuid),
+                                    &value,
+                                    offset,
+                                ) {
+                                    Ok(()) => RequestResponse::Success,
+                                    Err(response) => response,
+                                }
+                            }
+                            Ok(result) => result.response,
+                            Err(response) => response,
+                        };
+                        if response == RequestResponse::Success {
+                            request.Respond().unwrap();
+                            return;
+                        }
+                        request
+                            .RespondWithProtocolError(response.to_gatt_protocol_error())
+                            .unwrap();
+                    }
+                });
+
+                return Ok(());
+            },
+        )
+    }
+
+    pub fn create_descriptor_read_handler(
+        &self,
+        service_uuid: Uuid,
+        characteristic_uuid: Uuid,
+    ) -> TypedEventHandler<GattLocalDescriptor, GattReadRequestedEventArgs> {
+        let sender_tx: Sender<PeripheralEvent> = self.sender_tx.clone();
+        let response_timeout = self.response_timeout.clone();
+        let client_mtus = self.client_mtus.clone();
+        let sessions = self.sessions.clone();
+
+        TypedEventHandler::new(
+            move |originator: &Option<GattLocalDescriptor>,
+                  args: &Option<GattReadRequestedEventArgs>| {
+                let event_args: &GattReadRequestedEventArgs = args.as_ref().unwrap();
+                let descriptor = originator.as_ref().unwrap();
+
+                futures::executor::block_on(async {
+                    if let Ok(request) = event_args.GetRequestAsync().unwrap().await {
+                        let client = sessions.client(&event_args.Session().unwrap());
+                        let mtu = client_mtus.get(&client);
+                        let descriptor_uuid = to_uuid(&descriptor.Uuid().unwrap());
+                        let (resp_tx, resp_rx) = oneshot::channel::<ReadRequestResponse>();
+                        if let Err(e) = sender_tx
+                            .send(PeripheralEvent::DescriptorReadRequest {
+                                request: DescriptorRequest {
+                                    client: client.clone(),
                                     service: service_uuid,
-                                    characteristic: char_uuid,
+                                    characteristic: characteristic_uuid,
+                                    descriptor: descriptor_uuid,
+                                    mtu,
+                                    link: Some(LinkType::Le),
                                 },
-                                value: buffer_to_vec(&request.Value().unwrap()),
                                 offset: request.Offset().unwrap() as u64,
                                 responder: resp_tx,
                             })
@@ -249,19 +412,103 @@ impl WinEventHandler {
                             return;
                         }
 
-                        if let Ok(result) = resp_rx.await {
-                            if result.response == RequestResponse::Success {
-                                request.Respond().unwrap();
-                                return;
+                        let peripheral_request = PeripheralRequest {
+                            client,
+                            service: service_uuid,
+                            characteristic: characteristic_uuid,
+                            mtu,
+                            link: Some(LinkType::Le),
+                        };
+                        let result = response_timeout
+                            .wait(
+                                &sender_tx,
+                                peripheral_request,
+                                Some(descriptor_uuid),
+                                resp_rx,
+                            )
+                            .await;
+                        let result = match result {
+                            Ok(result) if result.response == RequestResponse::Success => {
+                                Ok(result.value)
                             }
-                            request
-                                .RespondWithProtocolError(result.response.to_gatt_protocol_error())
-                                .unwrap();
+                            Ok(result) => Err(result.response),
+                            Err(response) => Err(response),
+                        };
+                        respond_to_read(&request, result);
+                    }
+                });
+
+                return Ok(());
+            },
+        )
+    }
+
+    pub fn create_descriptor_write_handler(
+        &self,
+        service_uuid: Uuid,
+        characteristic_uuid: Uuid,
+    ) -> TypedEventHandler<GattLocalDescriptor, GattWriteRequestedEventArgs> {
+        let sender_tx = self.sender_tx.clone();
+        let response_timeout = self.response_timeout.clone();
+        let client_mtus = self.client_mtus.clone();
+        let sessions = self.sessions.clone();
+
+        TypedEventHandler::new(
+            move |originator: &Option<GattLocalDescriptor>,
+                  args: &Option<GattWriteRequestedEventArgs>| {
+                let event_args = args.as_ref().unwrap();
+                let descriptor = originator.as_ref().unwrap();
+                futures::executor::block_on(async {
+                    if let Ok(request) = event_args.GetRequestAsync().unwrap().await {
+                        let client = sessions.client(&event_args.Session().unwrap());
+                        let mtu = client_mtus.get(&client);
+                        let descriptor_uuid = to_uuid(&descriptor.Uuid().unwrap());
+                        let (resp_tx, resp_rx) = oneshot::channel::<WriteRequestResponse>();
+                        if let Err(e) = sender_tx
+                            .send(PeripheralEvent::DescriptorWriteRequest {
+                                request: DescriptorRequest {
+                                    client: client.clone(),
+                                    service: service_uuid,
+                                    characteristic: characteristic_uuid,
+                                    descriptor: descriptor_uuid,
+                                    mtu,
+                                    link: Some(LinkType::Le),
+                                },
+                                value: buffer_to_vec(&request.Value().unwrap()),
+                                offset: request.Offset().unwrap() as u64,
+                                responder: resp_tx,
+                            })
+                            .await
+                        {
+                            log::error!("Error sending delegate event: {}", e);
                             return;
                         }
 
+                        let peripheral_request = PeripheralRequest {
+                            client,
+                            service: service_uuid,
+                            characteristic: characteristic_uuid,
+                            mtu,
+                            link: Some(LinkType::Le),
+                        };
+                        let result = response_timeout
+                            .wait(
+                                &sender_tx,
+                                peripheral_request,
+                                Some(descriptor_uuid),
+                                resp_rx,
+                            )
+                            .await;
+                        let response = match result {
+                            Ok(result) => result.response,
+                            Err(response) => response,
+                        };
+                        if response == RequestResponse::Success {
+                            request.Respond().unwrap();
+                            return;
+                        }
                         request
-                            .RespondWithProtocolError(GattProtocolError::UnlikelyError().unwrap())
+                            .RespondWithProtocolError(response.to_gatt_protocol_error())
                             .unwrap();
                     }
                 });
@@ -272,18 +519,65 @@ impl WinEventHandler {
     }
 }
 
+fn respond_to_read(request: &GattReadRequest, result: Result<Vec<u8>, RequestResponse>) {
+    match result {
+        Ok(value) => request.RespondWithValue(&vec_to_buffer(value)).unwrap(),
+        Err(response) => request
+            .RespondWithProtocolError(response.to_gatt_protocol_error())
+            .unwrap(),
+    }
+}
+
+// Sessions of the clients seen in requests and subscriptions, held until they close.
+// Windows does not report connections, a closed session is the only sign of a disconnection.
+#[derive(Clone)]
+struct Sessions {
+    sessions: Arc<Mutex<HashMap<String, GattSession>>>,
+    client_mtus: ClientMtus,
+    long_reads: LongReads,
+}
+
+impl Sessions {
+    // Client of the session, whose MaxPduSize is the ATT MTU negotiated with it
+    fn client(&self, session: &GattSession) -> String {
+        let client = device_id_from_session(session.clone());
+        self.client_mtus.update(&client, session.MaxPduSize().ok());
+
+        let mut sessions = self.sessions.lock().unwrap();
+        if !sessions.contains_key(&client) {
+            let this = self.clone();
+            let closed_client = client.clone();
+            let handler = TypedEventHandler::new(
+                move |_: &Option<GattSession>, args: &Option<GattSessionStatusChangedEventArgs>| {
+                    if let Some(args) = args {
+                        if args.Status()? == GattSessionStatus::Closed {
+                            this.close(&closed_client);
+                        }
+                    }
+                    Ok(())
+                },
+            );
+            match session.SessionStatusChanged(&handler) {
+                Ok(_) => {
+                    sessions.insert(client.clone(), session.clone());
+                }
+                Err(err) => log::error!("Error watching the session of {client}: {err:?}"),
+            }
+        }
+        client
+    }
+
+    // Drop the state kept for the client, the session goes with it
+    fn close(&self, client: &str) {
+        self.sessions.lock().unwrap().remove(client);
+        self.client_mtus.remove(client);
+        self.long_reads.remove_client(client);
+    }
+}
+
 impl RequestResponse {
+    // Windows answers the protocol error with the ATT error code as is
     fn to_gatt_protocol_error(self) -> u8 {
-        let result = match self {
-            RequestResponse::Success => Ok(0),
-            RequestResponse::InvalidHandle => GattProtocolError::InvalidHandle(),
-            RequestResponse::RequestNotSupported => GattProtocolError::RequestNotSupported(),
-            RequestResponse::InvalidOffset => GattProtocolError::InvalidOffset(),
-            RequestResponse::UnlikelyError => GattProtocolError::UnlikelyError(),
-        };
-        if let Ok(value) = result {
-            return value;
-        }
-        return 0;
+        self.code()
     }
 }
@@ -8,7 +8,7 @@ pub trait ShortUuid {
 
 impl ShortUuid for Uuid {
     fn from_short(uuid: u16) -> Uuid {
-        return Uuid::from_fields(uuid.into(), 0, 0x1000, b"\x80\x00\x00\x80\x5F\x9B\x34\xFB");
+        Uuid::from_fields(uuid.into(), 0, 0x1000, b"\x80\x00\x00\x80\x5F\x9B\x34\xFB")
     }
 
     fn from_string(uuid_str: &str) -> Uuid {
@@ -0,0 +1,573 @@
+#![cfg(feature = "mock")]
+
+use async_trait::async_trait;
+use ble_peripheral_rust::{
+    advertisement::AdvertisementConfig,
+    error::ErrorType,
+    gatt::{
+        characteristic::{Characteristic, CharacteristicId},
+        handler::{GattHandler, GattHandlers},
+        peripheral_event::{
+            NotificationStatus, PeripheralEvent, PeripheralRequest, ReadRequestResponse,
+            RequestResponse, WriteRequestResponse,
+        },
+        properties::CharacteristicProperty,
+        service::Service,
+    },
+    mock::SimulatedCentral,
+    uuid::ShortUuid,
+    Peripheral, PeripheralImpl,
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::mpsc::{channel, Receiver};
+use uuid::Uuid;
+
+const CLIENT: &str = "00:11:22:33:44:55";
+
+fn service_uuid() -> Uuid {
+    Uuid::from_short(0x1234_u16)
+}
+
+fn char_uuid() -> Uuid {
+    Uuid::from_short(0x2A3D_u16)
+}
+
+fn indicate_uuid() -> Uuid {
+    Uuid::from_short(0x2A3E_u16)
+}
+
+fn characteristic_id() -> CharacteristicId {
+    CharacteristicId::new(service_uuid(), char_uuid())
+}
+
+// Peripheral serving one notifying and one indicate-only characteristic, with a central
+// connected to it
+async fn setup() -> (Peripheral, Receiver<PeripheralEvent>, SimulatedCentral) {
+    let (sender_tx, mut receiver_rx) = channel::<PeripheralEvent>(256);
+    let mut peripheral = Peripheral::new(sender_tx).await.unwrap();
+    peripheral
+        .add_service(&Service {
+            uuid: service_uuid(),
+            characteristics: vec![
+                Characteristic {
+                    uuid: char_uuid(),
+                    ..Default::default()
+                },
+                Characteristic {
+                    uuid: indicate_uuid(),
+                    properties: vec![CharacteristicProperty::Indicate],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    peripheral.start_serving().await.unwrap();
+    peripheral
+        .start_advertising("Test", &[service_uuid()])
+        .await
+        .unwrap();
+
+    let mut central = peripheral.simulated_central(CLIENT);
+    central.connect().await.unwrap();
+    assert!(matches!(
+        receiver_rx.recv().await,
+        Some(PeripheralEvent::ClientConnected { client, .. }) if client == CLIENT
+    ));
+    (peripheral, receiver_rx, central)
+}
+
+// Answer the next read request with `value`, returning the offset it was sent with
+async fn answer_read(receiver_rx: &mut Receiver<PeripheralEvent>, value: Vec<u8>) -> u64 {
+    match receiver_rx.recv().await {
+        Some(PeripheralEvent::ReadRequest {
+            offset, responder, ..
+        }) => {
+            responder
+                .send(ReadRequestResponse {
+                    value,
+                    response: RequestResponse::Success,
+                })
+                .unwrap();
+            offset
+        }
+        event => panic!("Expected a read request, got {event:?}"),
+    }
+}
+
+// Answer the next write request with `response`, returning the written value and offset
+async fn answer_write(
+    receiver_rx: &mut Receiver<PeripheralEvent>,
+    response: RequestResponse,
+) -> (Vec<u8>, u64) {
+    match receiver_rx.recv().await {
+        Some(PeripheralEvent::WriteRequest {
+            value,
+            offset,
+            responder,
+            ..
+        }) => {
+            responder.send(WriteRequestResponse { response }).unwrap();
+            (value, offset)
+        }
+        event => panic!("Expected a write request, got {event:?}"),
+    }
+}
+
+#[tokio::test]
+async fn duplicate_service_is_rejected() {
+    let (mut peripheral, _receiver_rx, _central) = setup().await;
+
+    let err = peripheral
+        .add_service(&Service {
+            uuid: service_uuid(),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert!(matches!(err.error_type(), ErrorType::InvalidService));
+}
+
+#[tokio::test]
+async fn duplicate_characteristic_is_rejected() {
+    let (mut peripheral, _receiver_rx, _central) = setup().await;
+
+    let characteristic = Characteristic {
+        uuid: char_uuid(),
+        ..Default::default()
+    };
+    let err = peripheral
+        .add_service(&Service {
+            uuid: Uuid::from_short(0x1235_u16),
+            characteristics: vec![characteristic.clone(), characteristic],
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert!(matches!(err.error_type(), ErrorType::InvalidService));
+}
+
+#[tokio::test]
+async fn connectable_advertisement_starts_serving() {
+    let (sender_tx, _receiver_rx) = channel::<PeripheralEvent>(256);
+    let mut peripheral = Peripheral::new(sender_tx).await.unwrap();
+
+    peripheral
+        .start_advertising_with(&AdvertisementConfig {
+            connectable: false,
+            ..AdvertisementConfig::new("Test", &[])
+        })
+        .await
+        .unwrap();
+    assert!(!peripheral.is_serving().await.unwrap());
+
+    peripheral.start_advertising("Test", &[]).await.unwrap();
+    assert!(peripheral.is_serving().await.unwrap());
+    peripheral.stop_advertising().await.unwrap();
+    assert!(peripheral.is_serving().await.unwrap());
+
+    peripheral.stop_serving().await.unwrap();
+    assert!(!peripheral.is_serving().await.unwrap());
+}
+
+#[tokio::test]
+async fn read_is_answered_by_the_application() {
+    let (_peripheral, mut receiver_rx, central) = setup().await;
+
+    let (value, offset) = tokio::join!(
+        central.read(service_uuid(), char_uuid(), 0),
+        answer_read(&mut receiver_rx, b"Hello".to_vec()),
+    );
+    assert_eq!(value, Ok(b"Hello".to_vec()));
+    assert_eq!(offset, 0);
+}
+
+#[tokio::test]
+async fn read_is_cut_to_the_mtu() {
+    let (_peripheral, mut receiver_rx, mut central) = setup().await;
+
+    let (value, _) = tokio::join!(
+        central.read(service_uuid(), char_uuid(), 0),
+        answer_read(&mut receiver_rx, vec![7; 100]),
+    );
+    assert_eq!(value.unwrap().len(), 22);
+
+    central.exchange_mtu(247).unwrap();
+    let (value, _) = tokio::join!(
+        central.read(service_uuid(), char_uuid(), 0),
+        answer_read(&mut receiver_rx, vec![7; 100]),
+    );
+    assert_eq!(value.unwrap().len(), 100);
+}
+
+#[tokio::test]
+async fn requests_carry_the_mtu() {
+    let (_peripheral, mut receiver_rx, mut central) = setup().await;
+    central.exchange_mtu(100).unwrap();
+
+    central
+        .subscribe(service_uuid(), char_uuid())
+        .await
+        .unwrap();
+    let Some(PeripheralEvent::CharacteristicSubscriptionUpdate { request, .. }) =
+        receiver_rx.recv().await
+    else {
+        panic!("Expected a subscription update");
+    };
+    assert_eq!(request.mtu, Some(100));
+    assert_eq!(request.max_notification_length(), Some(97));
+    assert_eq!(request.max_read_length(), Some(99));
+}
+
+#[tokio::test]
+async fn read_of_unknown_characteristic_fails() {
+    let (_peripheral, _receiver_rx, central) = setup().await;
+
+    let result = central
+        .read(service_uuid(), Uuid::from_short(0x2A00_u16), 0)
+        .await;
+    assert_eq!(result, Err(RequestResponse::InvalidHandle));
+}
+
+#[tokio::test]
+async fn write_is_delivered_to_the_application() {
+    let (_peripheral, mut receiver_rx, central) = setup().await;
+
+    let (result, (value, offset)) = tokio::join!(
+        central.write(service_uuid(), char_uuid(), b"Hello".to_vec(), 0),
+        answer_write(&mut receiver_rx, RequestResponse::Success),
+    );
+    assert_eq!(result, Ok(()));
+    assert_eq!(value, b"Hello".to_vec());
+    assert_eq!(offset, 0);
+}
+
+#[tokio::test]
+async fn write_rejected_by_the_application_fails() {
+    let (_peripheral, mut receiver_rx, central) = setup().await;
+
+    let (result, _) = tokio::join!(
+        central.write(service_uuid(), char_uuid(), b"Hello".to_vec(), 0),
+        answer_write(
+            &mut receiver_rx,
+            RequestResponse::InvalidAttributeValueLength
+        ),
+    );
+    assert_eq!(result, Err(RequestResponse::InvalidAttributeValueLength));
+}
+
+#[tokio::test]
+async fn write_without_property_is_not_permitted() {
+    let (_peripheral, _receiver_rx, central) = setup().await;
+
+    let result = central
+        .write(service_uuid(), indicate_uuid(), b"Hello".to_vec(), 0)
+        .await;
+    assert_eq!(result, Err(RequestResponse::WriteNotPermitted));
+}
+
+#[tokio::test]
+async fn subscribed_central_receives_notifications() {
+    let (mut peripheral, mut receiver_rx, mut central) = setup().await;
+
+    central
+        .subscribe(service_uuid(), char_uuid())
+        .await
+        .unwrap();
+    assert!(matches!(
+        receiver_rx.recv().await,
+        Some(PeripheralEvent::CharacteristicSubscriptionUpdate { request, subscribed: true })
+            if request.client == CLIENT && request.characteristic == char_uuid()
+    ));
+
+    let results = peripheral
+        .update_characteristic_with_results(characteristic_id(), b"Ping!".to_vec(), None)
+        .await
+        .unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].client, CLIENT);
+    assert_eq!(results[0].status, NotificationStatus::Sent);
+
+    let notification = central.notification().await.unwrap();
+    assert_eq!(notification.characteristic, char_uuid());
+    assert_eq!(notification.value, b"Ping!".to_vec());
+
+    central
+        .unsubscribe(service_uuid(), char_uuid())
+        .await
+        .unwrap();
+    assert!(matches!(
+        receiver_rx.recv().await,
+        Some(PeripheralEvent::CharacteristicSubscriptionUpdate {
+            subscribed: false,
+            ..
+        })
+    ));
+    peripheral
+        .update_characteristic(characteristic_id(), b"Ping!".to_vec())
+        .await
+        .unwrap();
+    assert_eq!(central.try_notification(), None);
+}
+
+#[tokio::test]
+async fn indications_are_confirmed() {
+    let (mut peripheral, mut receiver_rx, mut central) = setup().await;
+
+    central
+        .subscribe(service_uuid(), indicate_uuid())
+        .await
+        .unwrap();
+    receiver_rx.recv().await.unwrap();
+
+    let results = peripheral
+        .update_characteristic_with_results(
+            CharacteristicId::new(service_uuid(), indicate_uuid()),
+            b"Ping!".to_vec(),
+            Some(&[CLIENT.to_string(), "66:77:88:99:AA:BB".to_string()]),
+        )
+        .await
+        .unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].status, NotificationStatus::Confirmed);
+    assert_eq!(results[1].status, NotificationStatus::ClientGone);
+    assert!(central.try_notification().is_some());
+}
+
+#[tokio::test]
+async fn disconnect_ends_subscriptions() {
+    let (mut peripheral, mut receiver_rx, mut central) = setup().await;
+
+    central
+        .subscribe(service_uuid(), char_uuid())
+        .await
+        .unwrap();
+    receiver_rx.recv().await.unwrap();
+
+    central.disconnect().await.unwrap();
+    assert!(matches!(
+        receiver_rx.recv().await,
+        Some(PeripheralEvent::CharacteristicSubscriptionUpdate {
+            subscribed: false,
+            ..
+        })
+    ));
+    assert!(matches!(
+        receiver_rx.recv().await,
+        Some(PeripheralEvent::ClientDisconnected { client, .. }) if client == CLIENT
+    ));
+
+    let results = peripheral
+        .update_characteristic_with_results(characteristic_id(), b"Ping!".to_vec(), None)
+        .await
+        .unwrap();
+    assert!(results.is_empty());
+}
+
+#[tokio::test]
+async fn value_store_answers_reads_and_keeps_writes() {
+    let (mut peripheral, mut receiver_rx, central) = setup().await;
+    peripheral.value_store().set_enabled(true);
+
+    peripheral
+        .update_characteristic(characteristic_id(), b"Stored".to_vec())
+        .await
+        .unwrap();
+    let value = central.read(service_uuid(), char_uuid(), 0).await;
+    assert_eq!(value, Ok(b"Stored".to_vec()));
+    let value = central.read(service_uuid(), char_uuid(), 2).await;
+    assert_eq!(value, Ok(b"ored".to_vec()));
+
+    let (result, _) = tokio::join!(
+        central.write(service_uuid(), char_uuid(), b"Written".to_vec(), 0),
+        answer_write(&mut receiver_rx, RequestResponse::Success),
+    );
+    assert_eq!(result, Ok(()));
+    assert_eq!(
+        peripheral.value_store().get(characteristic_id()),
+        Some(b"Written".to_vec())
+    );
+    assert!(receiver_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn value_store_rejects_writes_past_the_end() {
+    let (mut peripheral, mut receiver_rx, central) = setup().await;
+    peripheral.value_store().set_enabled(true);
+
+    peripheral
+        .update_characteristic(characteristic_id(), b"Stored".to_vec())
+        .await
+        .unwrap();
+    let (result, _) = tokio::join!(
+        central.write(service_uuid(), char_uuid(), b"More".to_vec(), 6),
+        answer_write(&mut receiver_rx, RequestResponse::Success),
+    );
+    assert_eq!(result, Ok(()));
+    assert_eq!(
+        peripheral.value_store().get(characteristic_id()),
+        Some(b"StoredMore".to_vec())
+    );
+
+    let (result, _) = tokio::join!(
+        central.write(service_uuid(), char_uuid(), b"Gap".to_vec(), 12),
+        answer_write(&mut receiver_rx, RequestResponse::Success),
+    );
+    assert_eq!(result, Err(RequestResponse::InvalidOffset));
+    assert_eq!(
+        peripheral.value_store().get(characteristic_id()),
+        Some(b"StoredMore".to_vec())
+    );
+}
+
+#[tokio::test]
+async fn long_read_is_sliced_from_the_first_response() {
+    let (peripheral, mut receiver_rx, central) = setup().await;
+    peripheral.long_reads().set_enabled(true);
+    let value: Vec<u8> = (0..40).collect();
+
+    let (first, offset) = tokio::join!(
+        central.read(service_uuid(), char_uuid(), 0),
+        answer_read(&mut receiver_rx, value.clone()),
+    );
+    assert_eq!(offset, 0);
+    assert_eq!(first, Ok(value[..22].to_vec()));
+
+    // The rest is read without asking the application again
+    let rest = central.read(service_uuid(), char_uuid(), 22).await;
+    assert_eq!(rest, Ok(value[22..].to_vec()));
+    assert!(receiver_rx.try_recv().is_err());
+
+    // The short chunk ended the long read, the next one asks the application again
+    let (next, offset) = tokio::join!(
+        central.read(service_uuid(), char_uuid(), 22),
+        answer_read(&mut receiver_rx, b"New".to_vec()),
+    );
+    assert_eq!(offset, 0);
+    assert_eq!(next, Err(RequestResponse::InvalidOffset));
+}
+
+#[tokio::test]
+async fn long_read_is_dropped_on_disconnect() {
+    let (peripheral, mut receiver_rx, mut central) = setup().await;
+    peripheral.long_reads().set_enabled(true);
+
+    let (first, _) = tokio::join!(
+        central.read(service_uuid(), char_uuid(), 0),
+        answer_read(&mut receiver_rx, vec![7; 40]),
+    );
+    assert_eq!(first.unwrap().len(), 22);
+
+    central.disconnect().await.unwrap();
+    central.connect().await.unwrap();
+    while !matches!(
+        receiver_rx.recv().await,
+        Some(PeripheralEvent::ClientConnected { .. })
+    ) {}
+
+    let (rest, offset) = tokio::join!(
+        central.read(service_uuid(), char_uuid(), 22),
+        answer_read(&mut receiver_rx, vec![8; 40]),
+    );
+    assert_eq!(offset, 0);
+    assert_eq!(rest, Ok(vec![8; 18]));
+}
+
+#[tokio::test]
+async fn prepared_writes_are_reassembled() {
+    let (peripheral, mut receiver_rx, mut central) = setup().await;
+    peripheral.prepared_writes().set_enabled(true);
+
+    central
+        .prepare_write(service_uuid(), char_uuid(), b"Hello, ".to_vec(), 0)
+        .await
+        .unwrap();
+    central
+        .prepare_write(service_uuid(), char_uuid(), b"World!".to_vec(), 7)
+        .await
+        .unwrap();
+    let (result, (value, offset)) = tokio::join!(
+        central.execute_write(),
+        answer_write(&mut receiver_rx, RequestResponse::Success),
+    );
+    assert_eq!(result, Ok(()));
+    assert_eq!(value, b"Hello, World!".to_vec());
+    assert_eq!(offset, 0);
+}
+
+#[tokio::test]
+async fn prepared_writes_past_the_max_length_fail() {
+    let (peripheral, _receiver_rx, mut central) = setup().await;
+    peripheral.prepared_writes().set_enabled(true);
+    peripheral.prepared_writes().set_max_length(8);
+
+    let result = central
+        .prepare_write(service_uuid(), char_uuid(), b"Hello, World!".to_vec(), 0)
+        .await;
+    assert_eq!(result, Err(RequestResponse::InvalidAttributeValueLength));
+}
+
+#[tokio::test]
+async fn cancelled_prepared_writes_are_reported() {
+    let (peripheral, mut receiver_rx, mut central) = setup().await;
+    peripheral.prepared_writes().set_enabled(true);
+
+    central
+        .prepare_write(service_uuid(), char_uuid(), b"Hello".to_vec(), 0)
+        .await
+        .unwrap();
+    central.cancel_write().await.unwrap();
+    assert!(matches!(
+        receiver_rx.recv().await,
+        Some(PeripheralEvent::PreparedWriteCancelled { request })
+            if request.characteristic == char_uuid()
+    ));
+}
+
+// Records the written values, taking its time with the first one
+#[derive(Default)]
+struct SlowRecorder {
+    values: Mutex<Vec<Vec<u8>>>,
+}
+
+#[async_trait]
+impl GattHandler for SlowRecorder {
+    async fn on_write(
+        &self,
+        _request: PeripheralRequest,
+        value: Vec<u8>,
+        _offset: u64,
+    ) -> WriteRequestResponse {
+        if value == b"First" {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        self.values.lock().unwrap().push(value);
+        WriteRequestResponse {
+            response: RequestResponse::Success,
+        }
+    }
+}
+
+#[tokio::test]
+async fn handler_sees_writes_in_order() {
+    let (_peripheral, receiver_rx, central) = setup().await;
+    let recorder = Arc::new(SlowRecorder::default());
+    let mut handlers = GattHandlers::new();
+    handlers.add_service_handler(service_uuid(), recorder.clone());
+    tokio::spawn(handlers.run(receiver_rx));
+
+    let (first, second) = tokio::join!(
+        central.write(service_uuid(), char_uuid(), b"First".to_vec(), 0),
+        central.write(service_uuid(), char_uuid(), b"Second".to_vec(), 0),
+    );
+    assert_eq!(first, Ok(()));
+    assert_eq!(second, Ok(()));
+    assert_eq!(
+        *recorder.values.lock().unwrap(),
+        vec![b"First".to_vec(), b"Second".to_vec()]
+    );
+}